@@ -0,0 +1,299 @@
+#[cfg(feature = "online")]
+use std::fmt;
+
+use bevy::prelude::*;
+
+use crate::types::{GameState, GameType};
+
+#[cfg(not(target_arch = "wasm32"))]
+const USAGE: &str = "usage: pong [--synctest] [--check-distance N] [--server URL] [--room NAME] \
+                     [--input-delay N|auto] [--max-prediction N] [--fps N]";
+
+/// Environment variables that can stand in for a command line flag, the flag wins if both are set
+#[cfg(all(feature = "online", not(target_arch = "wasm32")))]
+const ENV_VARS: [(&str, &str); 2] = [("PONG_SERVER", "--server"), ("PONG_ROOM", "--room")];
+
+/// Query string parameters of the page that set a flag on the web
+#[cfg(all(feature = "online", target_arch = "wasm32"))]
+const QUERY_PARAMS: [(&str, &str); 5] = [
+    ("server", "--server"),
+    ("room", "--room"),
+    ("input-delay", "--input-delay"),
+    ("max-prediction", "--max-prediction"),
+    ("fps", "--fps"),
+];
+
+#[cfg(feature = "online")]
+const DEFAULT_SERVER: &str = "wss://pong-signalling-server.fly.dev";
+
+/// Largest input delay that can be set, or that is picked automatically
+#[cfg(feature = "online")]
+pub(crate) const MAX_INPUT_DELAY: usize = 8;
+/// Largest number of frames GGRS may predict ahead of the confirmed inputs
+#[cfg(feature = "online")]
+pub(crate) const MAX_PREDICTION_WINDOW: usize = 16;
+/// Highest simulation rate of online matches
+#[cfg(feature = "online")]
+const MAX_FPS: usize = 240;
+
+/// Frames the local inputs are held back before they are used, hiding the network latency at the
+/// cost of responsiveness
+#[cfg(feature = "online")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InputDelay {
+    Fixed(usize),
+    /// Picked from the round trip time to the other players, measured in the lobby
+    Auto,
+}
+
+#[cfg(feature = "online")]
+impl fmt::Display for InputDelay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InputDelay::Fixed(frames) => write!(f, "{frames}"),
+            InputDelay::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Options given on the command line, in the environment or in the query string of the page
+#[derive(Resource, Debug, Clone)]
+#[cfg_attr(not(feature = "online"), derive(Default))]
+pub(crate) struct Args {
+    /// Run a GGRS SyncTest session against ourselves instead of showing the menu
+    #[cfg(feature = "online")]
+    pub(crate) synctest: bool,
+    /// How many frames SyncTest rolls back and resimulates every frame
+    #[cfg(feature = "online")]
+    pub(crate) check_distance: usize,
+    /// Matchbox signalling server used to find other players
+    #[cfg(feature = "online")]
+    pub(crate) server: String,
+    /// Room on the signalling server, only players in the same room are matched up
+    #[cfg(feature = "online")]
+    pub(crate) room: String,
+    /// Input delay of online matches
+    #[cfg(feature = "online")]
+    pub(crate) input_delay: InputDelay,
+    /// Maximum number of frames GGRS predicts before it waits for the other players
+    #[cfg(feature = "online")]
+    pub(crate) max_prediction: usize,
+    /// Simulation rate of online matches, every player needs to use the same
+    #[cfg(feature = "online")]
+    pub(crate) fps: usize,
+}
+
+#[cfg(feature = "online")]
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            synctest: false,
+            check_distance: 2,
+            server: DEFAULT_SERVER.to_string(),
+            room: "pong".to_string(),
+            input_delay: InputDelay::Fixed(2),
+            max_prediction: 12,
+            fps: 60,
+        }
+    }
+}
+
+impl Args {
+    /// Read the options from the environment and the process arguments, exiting with a usage
+    /// message if they are invalid
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn from_env() -> Self {
+        match Args::parse(|var| std::env::var(var).ok(), std::env::args().skip(1)) {
+            Ok(args) => args,
+            Err(err) => {
+                eprintln!("{err}\n{USAGE}");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    /// Options from the environment variables looked up with `var` and the flags in `args`, which
+    /// are applied last so they win
+    #[cfg(not(target_arch = "wasm32"))]
+    fn parse(
+        var: impl Fn(&str) -> Option<String>,
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Self, String> {
+        #[cfg(feature = "online")]
+        let env = ENV_VARS.iter().flat_map(|&(name, flag)| {
+            var(name)
+                .map(|value| [flag.to_string(), value])
+                .into_iter()
+                .flatten()
+        });
+        #[cfg(not(feature = "online"))]
+        let env = {
+            let _ = var;
+            std::iter::empty()
+        };
+
+        let mut parsed = Args::default();
+        parsed.apply(env.chain(args))?;
+        Ok(parsed)
+    }
+
+    /// Read the options from the query string of the page, `?room=friends&fps=30`, invalid
+    /// values are reported on the console and left at their default
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) fn from_env() -> Self {
+        let mut args = Args::default();
+
+        #[cfg(feature = "online")]
+        {
+            let params = web_sys::window()
+                .and_then(|window| window.location().search().ok())
+                .and_then(|query| web_sys::UrlSearchParams::new_with_str(&query).ok());
+            for (param, flag) in QUERY_PARAMS {
+                let Some(value) = params.as_ref().and_then(|params| params.get(param)) else {
+                    continue;
+                };
+                if let Err(err) = args.apply([flag.to_string(), value]) {
+                    web_sys::console::error_1(&err.into());
+                }
+            }
+        }
+
+        args
+    }
+
+    /// State the game starts in, the main menu unless a mode was picked on the command line
+    pub(crate) fn initial_state(&self) -> (GameState, GameType) {
+        #[cfg(feature = "online")]
+        if self.synctest {
+            return (GameState::Ingame, GameType::Online);
+        }
+        (GameState::MainMenu, GameType::Local)
+    }
+
+    /// Apply command line flags on top of the current options
+    #[cfg(feature = "online")]
+    fn apply(&mut self, args: impl IntoIterator<Item = String>) -> Result<(), String> {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--synctest" => self.synctest = true,
+                "--check-distance" => {
+                    let value = args
+                        .next()
+                        .ok_or("--check-distance needs a number of frames")?;
+                    self.check_distance = value
+                        .parse()
+                        .map_err(|_| format!("invalid check distance: {value}"))?;
+                }
+                "--server" => {
+                    self.server = args.next().ok_or("--server needs a url")?;
+                }
+                "--room" => {
+                    self.room = args.next().ok_or("--room needs a name")?;
+                }
+                "--input-delay" => {
+                    let value = args
+                        .next()
+                        .ok_or("--input-delay needs a number of frames or auto")?;
+                    self.input_delay = match value.as_str() {
+                        "auto" => InputDelay::Auto,
+                        _ => match value.parse() {
+                            Ok(frames) if frames <= MAX_INPUT_DELAY => InputDelay::Fixed(frames),
+                            _ => {
+                                return Err(format!(
+                                    "invalid input delay: {value}, expected auto or 0 to \
+                                     {MAX_INPUT_DELAY}"
+                                ))
+                            }
+                        },
+                    };
+                }
+                "--max-prediction" => {
+                    let value = args
+                        .next()
+                        .ok_or("--max-prediction needs a number of frames")?;
+                    self.max_prediction = match value.parse() {
+                        Ok(frames) if (1..=MAX_PREDICTION_WINDOW).contains(&frames) => frames,
+                        _ => {
+                            return Err(format!(
+                                "invalid prediction window: {value}, expected 1 to \
+                                 {MAX_PREDICTION_WINDOW}"
+                            ))
+                        }
+                    };
+                }
+                "--fps" => {
+                    let value = args.next().ok_or("--fps needs a number")?;
+                    self.fps = match value.parse() {
+                        Ok(fps) if (1..=MAX_FPS).contains(&fps) => fps,
+                        _ => return Err(format!("invalid fps: {value}, expected 1 to {MAX_FPS}")),
+                    };
+                }
+                _ => return Err(format!("unknown argument: {arg}")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Every option belongs to online matches, so without them there is nothing to apply
+    #[cfg(not(feature = "online"))]
+    fn apply(&mut self, args: impl IntoIterator<Item = String>) -> Result<(), String> {
+        match args.into_iter().next() {
+            Some(arg) => Err(format!("unknown argument: {arg}")),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "online", not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    fn parse(env: &[(&str, &str)], args: &[&str]) -> Result<Args, String> {
+        let var = |name: &str| {
+            env.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        };
+        Args::parse(var, args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn flags_win_over_the_environment() {
+        let env = [
+            ("PONG_SERVER", "ws://10.0.0.5:3536"),
+            ("PONG_ROOM", "office"),
+        ];
+        let args = parse(&env, &["--room", "friends", "--input-delay", "auto"]).unwrap();
+        assert_eq!(args.server, "ws://10.0.0.5:3536");
+        assert_eq!(args.room, "friends");
+        assert_eq!(args.input_delay, InputDelay::Auto);
+
+        let args = parse(&[], &[]).unwrap();
+        assert_eq!(args.server, DEFAULT_SERVER);
+        assert_eq!(args.room, "pong");
+    }
+
+    #[test]
+    fn invalid_flags_explain_themselves() {
+        let err = |args: &[&str]| parse(&[], args).unwrap_err();
+        assert_eq!(err(&["--room"]), "--room needs a name");
+        assert_eq!(err(&["--players", "2"]), "unknown argument: --players");
+        assert_eq!(
+            err(&["--input-delay", "9"]),
+            "invalid input delay: 9, expected auto or 0 to 8"
+        );
+        assert_eq!(
+            err(&["--max-prediction", "0"]),
+            "invalid prediction window: 0, expected 1 to 16"
+        );
+        assert_eq!(
+            err(&["--fps", "4294967296"]),
+            "invalid fps: 4294967296, expected 1 to 240"
+        );
+        assert_eq!(
+            parse(&[("PONG_ROOM", "office")], &["--check-distance", "x"]).unwrap_err(),
+            "invalid check distance: x"
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use std::time::Duration;
+
+use bevy::{prelude::*, window::CursorMoved};
+use iyes_loopless::prelude::*;
+
+use crate::{
+    local::{LocalGameType, TIMESTEP},
+    pong::{
+        reset_court, reset_match_state, spawn_court, sync_transforms, Ball, Court, LeftPlayer,
+        Paddle, PongConfig, PongState, Position, Timestep,
+    },
+    types::GameState,
+};
+
+/// Time the main menu has to sit untouched before the demo starts
+const IDLE_TIME: Duration = Duration::from_secs(15);
+
+/// Opacity of the demo court, so it stays in the background of the menu
+const DEMO_ALPHA: f32 = 0.3;
+
+/// Runs before the menu buttons, which pick the game type of a match started while the demo
+/// is stopped
+#[derive(SystemLabel)]
+pub(crate) struct AttractInput;
+
+/// Demo of the AI playing itself behind the main menu, shown once nobody touched the menu for a
+/// while
+#[derive(Resource)]
+struct AttractMode {
+    idle: Timer,
+    playing: bool,
+}
+
+impl Default for AttractMode {
+    fn default() -> Self {
+        AttractMode {
+            idle: Timer::new(IDLE_TIME, TimerMode::Once),
+            playing: false,
+        }
+    }
+}
+
+fn start_demo(commands: &mut Commands, attract: &mut AttractMode, config: &PongConfig) {
+    attract.playing = true;
+    reset_match_state(commands);
+    spawn_court(commands, config);
+    commands.insert_resource(Timestep(TIMESTEP));
+    commands.insert_resource(NextState(LocalGameType::Demo));
+}
+
+fn stop_demo(
+    commands: &mut Commands,
+    attract: &mut AttractMode,
+    court_query: &Query<Entity, With<Court>>,
+) {
+    attract.playing = false;
+    attract.idle.reset();
+    for court in court_query {
+        commands.entity(court).despawn_recursive();
+    }
+    reset_match_state(commands);
+    commands.insert_resource(NextState(LocalGameType::SinglePlayer));
+}
+
+fn reset_idle_time(mut attract: ResMut<AttractMode>) {
+    attract.idle.reset();
+}
+
+/// Start the demo when the menu was left alone long enough, and stop it on any input
+#[allow(clippy::type_complexity)]
+fn attract_mode(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut attract: ResMut<AttractMode>,
+    config: Res<PongConfig>,
+    (keyboard, mouse, gamepad, touches): (
+        Res<Input<KeyCode>>,
+        Res<Input<MouseButton>>,
+        Res<Input<GamepadButton>>,
+        Res<Touches>,
+    ),
+    mut cursor_moved: EventReader<CursorMoved>,
+    court_query: Query<Entity, With<Court>>,
+) {
+    let input = keyboard.get_just_pressed().next().is_some()
+        || mouse.get_just_pressed().next().is_some()
+        || gamepad.get_just_pressed().next().is_some()
+        || touches.iter_just_pressed().next().is_some()
+        || cursor_moved.iter().next().is_some();
+
+    if input {
+        attract.idle.reset();
+        if attract.playing {
+            stop_demo(&mut commands, &mut attract, &court_query);
+        }
+    } else if attract.idle.tick(time.delta()).just_finished() {
+        start_demo(&mut commands, &mut attract, &config);
+    }
+}
+
+/// Play the demo again once a match in it is over
+fn restart_demo(
+    mut commands: Commands,
+    config: Res<PongConfig>,
+    pong_state: Res<PongState>,
+    mut paddle_query: Query<(&mut Paddle, &mut Position, Option<&LeftPlayer>), Without<Ball>>,
+    mut ball_query: Query<(&mut Ball, &mut Position), Without<Paddle>>,
+) {
+    if matches!(*pong_state, PongState::GameOver(_)) {
+        reset_match_state(&mut commands);
+        reset_court(&config, &mut paddle_query, &mut ball_query);
+    }
+}
+
+/// Fade out the demo court, runs after its sprites are spawned but before they are first drawn
+fn dim_demo(mut sprite_query: Query<&mut Sprite, Added<Sprite>>) {
+    for mut sprite in &mut sprite_query {
+        sprite.color.set_a(DEMO_ALPHA);
+    }
+}
+
+fn leave_demo(
+    mut commands: Commands,
+    mut attract: ResMut<AttractMode>,
+    court_query: Query<Entity, With<Court>>,
+) {
+    if attract.playing {
+        stop_demo(&mut commands, &mut attract, &court_query);
+    }
+}
+
+/// Plays the demo behind the main menu, the match itself runs on the systems of local matches
+pub(crate) struct AttractPlugin;
+
+impl Plugin for AttractPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<AttractMode>()
+            .add_enter_system(GameState::MainMenu, reset_idle_time)
+            .add_exit_system(GameState::MainMenu, leave_demo)
+            .add_system(
+                attract_mode
+                    .run_in_state(GameState::MainMenu)
+                    .label(AttractInput),
+            )
+            .add_system_set(
+                ConditionSet::new()
+                    .run_in_state(GameState::MainMenu)
+                    .run_in_state(LocalGameType::Demo)
+                    .with_system(restart_demo)
+                    .with_system(sync_transforms)
+                    .into(),
+            )
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                dim_demo.run_in_state(GameState::MainMenu),
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        input::{keyboard::KeyboardInput, ButtonState, InputPlugin},
+        time::TimePlugin,
+    };
+
+    use super::*;
+    use crate::{
+        controls::KeyBindings, fixed::FixedVec2, local::LocalPlugin, pong::PongPlugin,
+        types::GameType,
+    };
+
+    /// Move the clock on by one simulated tick and run a frame, so the tests don't wait in real time
+    fn tick(app: &mut App) {
+        let mut time = app.world.resource_mut::<Time>();
+        let now = time.last_update().unwrap_or_else(|| time.startup());
+        time.update_with_instant(now + TIMESTEP);
+        app.update();
+    }
+
+    #[test]
+    fn demo_plays_after_idling_until_a_key_is_pressed() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins.build().disable::<TimePlugin>())
+            .init_resource::<Time>()
+            .add_plugin(InputPlugin)
+            .init_resource::<Windows>()
+            .add_event::<CursorMoved>()
+            .init_resource::<KeyBindings>()
+            .add_loopless_state(GameState::MainMenu)
+            .add_loopless_state(GameType::Local)
+            .add_plugin(PongPlugin)
+            .add_plugin(LocalPlugin)
+            .add_plugin(AttractPlugin);
+        tick(&mut app);
+
+        let courts = |app: &mut App| app.world.query::<&Court>().iter(&app.world).count();
+        let local_game_type = |app: &App| {
+            app.world
+                .resource::<CurrentState<LocalGameType>>()
+                .0
+                .clone()
+        };
+        assert_eq!(courts(&mut app), 0);
+
+        let idle_time = IDLE_TIME - Duration::from_millis(1);
+        app.world
+            .resource_mut::<AttractMode>()
+            .idle
+            .set_elapsed(idle_time);
+        for _ in 0..3 {
+            tick(&mut app);
+        }
+        assert_eq!(courts(&mut app), 1);
+        assert_eq!(local_game_type(&app), LocalGameType::Demo);
+
+        // the AI serves and the ball moves once enough simulated ticks went by
+        let mut ball_query = app.world.query_filtered::<&Position, With<Ball>>();
+        for _ in 0..100 {
+            if *app.world.resource::<PongState>() == PongState::Playing
+                && **ball_query.single(&app.world) != FixedVec2::ZERO
+            {
+                break;
+            }
+            tick(&mut app);
+        }
+        assert_eq!(*app.world.resource::<PongState>(), PongState::Playing);
+        assert_ne!(**ball_query.single(&app.world), FixedVec2::ZERO);
+
+        app.world.send_event(KeyboardInput {
+            scan_code: 0,
+            key_code: Some(KeyCode::A),
+            state: ButtonState::Pressed,
+        });
+        for _ in 0..2 {
+            tick(&mut app);
+        }
+        assert_eq!(courts(&mut app), 0);
+        assert_eq!(local_game_type(&app), LocalGameType::SinglePlayer);
+    }
+}
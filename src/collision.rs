@@ -0,0 +1,140 @@
+use crate::fixed::{Fixed, FixedVec2};
+
+/// Axis-aligned box described by its center and half extents
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Aabb {
+    pub(crate) center: FixedVec2,
+    pub(crate) half_extents: FixedVec2,
+}
+
+impl Aabb {
+    pub(crate) fn new(center: FixedVec2, size: FixedVec2) -> Self {
+        Aabb {
+            center,
+            half_extents: size / Fixed::from_int(2),
+        }
+    }
+
+    /// Grow the box by the half extents of another box, so a sweep of that box's center against
+    /// the result is equivalent to sweeping the whole box.
+    pub(crate) fn expand(&self, half_extents: FixedVec2) -> Self {
+        Aabb {
+            center: self.center,
+            half_extents: self.half_extents + half_extents,
+        }
+    }
+
+    pub(crate) fn min(&self) -> FixedVec2 {
+        self.center - self.half_extents
+    }
+
+    pub(crate) fn max(&self) -> FixedVec2 {
+        self.center + self.half_extents
+    }
+
+    /// Snap the coordinate of `point` along `normal` onto the face of the box with that outward
+    /// normal.
+    pub(crate) fn snap_to_face(&self, point: &mut FixedVec2, normal: FixedVec2) {
+        for axis in 0..2 {
+            if normal[axis] > Fixed::ZERO {
+                point[axis] = self.max()[axis];
+            } else if normal[axis] < Fixed::ZERO {
+                point[axis] = self.min()[axis];
+            }
+        }
+    }
+
+    pub(crate) fn contains(&self, point: FixedVec2) -> bool {
+        let (min, max) = (self.min(), self.max());
+        point.x > min.x && point.x < max.x && point.y > min.y && point.y < max.y
+    }
+}
+
+/// Where along a sweep a hit happened and which face was hit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Contact {
+    /// Fraction of the sweep, in `[0, 1]`, at which the contact occurs
+    pub(crate) time: Fixed,
+    /// Outward normal of the face that was hit
+    pub(crate) normal: FixedVec2,
+}
+
+/// Sweep a point from `start` along `delta` against `target` and return the first contact.
+///
+/// Only contacts entering the box are reported, a point that starts inside or moves away from a
+/// face never hits it.
+pub(crate) fn sweep_point(start: FixedVec2, delta: FixedVec2, target: &Aabb) -> Option<Contact> {
+    let (min, max) = (target.min(), target.max());
+    let mut entry = Fixed::ZERO;
+    let mut exit = Fixed::ONE;
+    let mut normal = FixedVec2::ZERO;
+
+    for axis in 0..2 {
+        let (start, delta, min, max) = (start[axis], delta[axis], min[axis], max[axis]);
+        if delta.is_zero() {
+            if start <= min || start >= max {
+                return None;
+            }
+            continue;
+        }
+
+        let (near, far, face) = if delta > Fixed::ZERO {
+            ((min - start) / delta, (max - start) / delta, -Fixed::ONE)
+        } else {
+            ((max - start) / delta, (min - start) / delta, Fixed::ONE)
+        };
+
+        if near > entry {
+            entry = near;
+            normal = FixedVec2::ZERO;
+            normal[axis] = face;
+        }
+        exit = exit.min(far);
+
+        if entry > exit {
+            return None;
+        }
+    }
+
+    if normal == FixedVec2::ZERO {
+        // started inside the box, or never reached it
+        return None;
+    }
+
+    Some(Contact {
+        time: entry,
+        normal,
+    })
+}
+
+/// Sweep a point that is inside `bounds` along `delta` and return where it first leaves them.
+///
+/// The contact normal points back into the bounds, so reflecting against it keeps the point
+/// inside.
+pub(crate) fn sweep_inside(start: FixedVec2, delta: FixedVec2, bounds: &Aabb) -> Option<Contact> {
+    let (min, max) = (bounds.min(), bounds.max());
+    let mut contact: Option<Contact> = None;
+
+    for axis in 0..2 {
+        let (time, face) = if delta[axis] > Fixed::ZERO {
+            ((max[axis] - start[axis]) / delta[axis], -Fixed::ONE)
+        } else if delta[axis] < Fixed::ZERO {
+            ((min[axis] - start[axis]) / delta[axis], Fixed::ONE)
+        } else {
+            continue;
+        };
+
+        let time = time.max(Fixed::ZERO);
+        let earliest = match contact {
+            Some(contact) => time < contact.time,
+            None => true,
+        };
+        if time <= Fixed::ONE && earliest {
+            let mut normal = FixedVec2::ZERO;
+            normal[axis] = face;
+            contact = Some(Contact { time, normal });
+        }
+    }
+
+    contact
+}
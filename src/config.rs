@@ -0,0 +1,318 @@
+use std::time::Duration;
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use iyes_loopless::prelude::*;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    fixed::Fixed,
+    local::{AiHandicaps, LocalConfig},
+    pong::{spawn_court, Court, PongConfig, PongState, Side},
+    types::{GameState, GameType},
+};
+
+/// Config file in the assets folder, reloaded whenever it changes on disk
+const CONFIG_PATH: &str = "pong.config.ron";
+
+/// Every tunable of the game, as written in the config file
+#[derive(Debug, Clone, PartialEq, Deserialize, TypeUuid)]
+#[serde(deny_unknown_fields)]
+#[uuid = "5a1b6c3e-8f0d-4c57-9a8e-2d4f7b1c9e60"]
+pub(crate) struct ConfigFile {
+    pub(crate) game: PongConfig,
+    pub(crate) ai: AiHandicaps,
+}
+
+/// Read an optional duration written as a number of seconds, `Some(90.0)`
+pub(crate) fn optional_seconds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<f32>::deserialize(deserializer)?
+        .map(|seconds| Duration::try_from_secs_f32(seconds).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Everything wrong with the values in a config file that parsed
+fn validate(config: &ConfigFile) -> Vec<String> {
+    let mut errors = Vec::new();
+    let game = &config.game;
+
+    let mut positive = |name: &str, values: &[f32]| {
+        if !values.iter().all(|value| value.is_finite() && *value > 0.) {
+            errors.push(format!("game.{name} must be positive, got {values:?}"));
+        }
+    };
+    positive("court_size", &game.court_size);
+    positive("paddle_speed", &[game.paddle_speed]);
+    positive("ball_speed", &[game.ball_speed]);
+    positive("max_ball_speed", &[game.max_ball_speed]);
+    positive("paddle_size", &game.paddle_size);
+    positive("ball_size", &game.ball_size);
+    positive("dash_spacing", &[game.dash_spacing]);
+    positive("dash_size", &game.dash_size);
+    if let Some(momentum) = &game.paddle_momentum {
+        positive("paddle_momentum.acceleration", &[momentum.acceleration]);
+        positive("paddle_momentum.friction", &[momentum.friction]);
+    }
+    // the simulation divides by the speeds in fixed point, where the tiniest ones round to zero
+    for (name, speed) in [
+        ("paddle_speed", game.paddle_speed),
+        ("ball_speed", game.ball_speed),
+        ("max_ball_speed", game.max_ball_speed),
+    ] {
+        if speed > 0. && Fixed::from_f32(speed) == Fixed::ZERO {
+            errors.push(format!("game.{name} must be at least 1/65536, got {speed}"));
+        }
+    }
+
+    if !(game.players_distance_percentage > 0. && game.players_distance_percentage < 0.5) {
+        errors.push(format!(
+            "game.players_distance_percentage must be between 0 and 0.5, got {}",
+            game.players_distance_percentage
+        ));
+    }
+    if !(game.speed_up_per_hit >= 1. && game.speed_up_per_hit.is_finite()) {
+        errors.push(format!(
+            "game.speed_up_per_hit must be at least 1, got {}",
+            game.speed_up_per_hit
+        ));
+    }
+    if game.max_ball_speed < game.ball_speed {
+        errors.push(format!(
+            "game.max_ball_speed must be at least the serve speed of {}, got {}",
+            game.ball_speed, game.max_ball_speed
+        ));
+    }
+    if !(game.max_deflection_angle > 0. && game.max_deflection_angle < 90.) {
+        errors.push(format!(
+            "game.max_deflection_angle must be between 0 and 90 degrees, got {}",
+            game.max_deflection_angle
+        ));
+    }
+    if !(0. ..=1.).contains(&game.english) {
+        errors.push(format!(
+            "game.english must be between 0 and 1, got {}",
+            game.english
+        ));
+    }
+    if game.paddle_size[1] >= game.court_size[1] || game.ball_size[1] >= game.court_size[1] {
+        errors.push("game.paddle_size and game.ball_size must fit in the court".to_string());
+    }
+
+    let rules = &game.rules;
+    if rules.points_to_win == 0 {
+        errors.push("game.rules.points_to_win must be at least 1".to_string());
+    }
+    if rules.best_of % 2 != 1 {
+        errors.push(format!(
+            "game.rules.best_of must be an odd number of games, got {}",
+            rules.best_of
+        ));
+    }
+    if rules.time_limit == Some(Duration::ZERO) {
+        errors.push("game.rules.time_limit must be longer than 0 seconds".to_string());
+    }
+
+    for (difficulty, handicap) in config.ai.iter() {
+        let level = difficulty.to_string().to_lowercase();
+        if !(0. ..=1.).contains(&handicap.view_percentage) {
+            errors.push(format!(
+                "ai.{level}.view_percentage must be between 0 and 1, got {}",
+                handicap.view_percentage
+            ));
+        }
+        if !(handicap.aim_error >= 0. && handicap.aim_error.is_finite()) {
+            errors.push(format!(
+                "ai.{level}.aim_error must not be negative, got {}",
+                handicap.aim_error
+            ));
+        }
+        if !(handicap.tracking_speed > 0. && handicap.tracking_speed <= 1.) {
+            errors.push(format!(
+                "ai.{level}.tracking_speed must be above 0 and at most 1, got {}",
+                handicap.tracking_speed
+            ));
+        }
+    }
+
+    errors
+}
+
+/// Parse and validate a config file, the error lists every problem found
+fn parse_config(bytes: &[u8]) -> Result<ConfigFile, String> {
+    let config: ConfigFile = ron::de::from_bytes(bytes).map_err(|err| err.to_string())?;
+    let errors = validate(&config);
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(config)
+}
+
+#[derive(Default)]
+struct ConfigLoader;
+
+impl AssetLoader for ConfigLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let config = parse_config(bytes).map_err(|err| {
+                bevy::asset::Error::msg(format!(
+                    "invalid config in {}, keeping the current one:\n{err}",
+                    load_context.path().display()
+                ))
+            })?;
+            load_context.set_default_asset(LoadedAsset::new(config));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["config.ron"]
+    }
+}
+
+/// The loaded config file, and whether it changed since it was last applied
+#[derive(Resource)]
+struct ConfigHandle {
+    handle: Handle<ConfigFile>,
+    changed: bool,
+}
+
+fn load_config(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(ConfigHandle {
+        handle: asset_server.load(CONFIG_PATH),
+        changed: false,
+    });
+}
+
+fn watch_config(
+    mut events: EventReader<AssetEvent<ConfigFile>>,
+    mut config_handle: ResMut<ConfigHandle>,
+) {
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle }
+                if *handle == config_handle.handle =>
+            {
+                config_handle.changed = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Online peers compare their configs in the lobby and need the same one for as long as the match
+/// runs, so changes wait until they are back in the main menu
+fn config_may_change(
+    game_state: Res<CurrentState<GameState>>,
+    game_type: Res<CurrentState<GameType>>,
+) -> bool {
+    match (&game_type.0, &game_state.0) {
+        #[cfg(feature = "online")]
+        (GameType::Online, GameState::Lobby | GameState::Ingame) => false,
+        _ => true,
+    }
+}
+
+/// Put a changed config in place. A local match in progress gets a new court built to it, with
+/// the score kept and the rally served again.
+fn apply_config(
+    mut commands: Commands,
+    mut config_handle: ResMut<ConfigHandle>,
+    configs: Res<Assets<ConfigFile>>,
+    mut pong_config: ResMut<PongConfig>,
+    mut local_config: ResMut<LocalConfig>,
+    mut pong_state: ResMut<PongState>,
+    court_query: Query<Entity, With<Court>>,
+) {
+    if !config_handle.changed {
+        return;
+    }
+    let Some(config) = configs.get(&config_handle.handle) else {
+        return;
+    };
+    config_handle.changed = false;
+    info!("applying config from {CONFIG_PATH}");
+
+    if local_config.ai_handicaps != config.ai {
+        local_config.ai_handicaps = config.ai.clone();
+    }
+    if *pong_config == config.game {
+        return;
+    }
+    *pong_config = config.game.clone();
+
+    for court in &court_query {
+        commands.entity(court).despawn_recursive();
+        spawn_court(&mut commands, &config.game);
+        if *pong_state == PongState::Playing {
+            *pong_state = PongState::Serve(Side::Left);
+        }
+    }
+}
+
+/// Loads the tunables from the config file and applies them again whenever the file changes
+pub(crate) struct ConfigPlugin;
+
+impl Plugin for ConfigPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<ConfigFile>()
+            .init_asset_loader::<ConfigLoader>()
+            .add_startup_system(load_config)
+            .add_system(watch_config.run_if_resource_exists::<ConfigHandle>())
+            .add_system(
+                apply_config
+                    .run_if_resource_exists::<ConfigHandle>()
+                    .run_if(config_may_change),
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shipped_config_matches_the_defaults() {
+        let config = parse_config(include_bytes!("../assets/pong.config.ron"))
+            .expect("the shipped config should be valid");
+        assert_eq!(config.game, PongConfig::default());
+        assert_eq!(config.ai, LocalConfig::default().ai_handicaps);
+    }
+
+    #[test]
+    fn invalid_configs_explain_themselves() {
+        let shipped = include_str!("../assets/pong.config.ron");
+
+        let typo = shipped.replace("ball_speed", "bal_speed");
+        let err = parse_config(typo.as_bytes()).unwrap_err();
+        assert!(err.contains("bal_speed"), "{err}");
+
+        let invalid = shipped
+            .replace("paddle_speed: 10.0", "paddle_speed: -1.0")
+            .replace("best_of: 1", "best_of: 2");
+        let err = parse_config(invalid.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.lines().collect::<Vec<_>>(),
+            [
+                "game.paddle_speed must be positive, got [-1.0]",
+                "game.rules.best_of must be an odd number of games, got 2",
+            ]
+        );
+
+        let too_slow = shipped.replace("paddle_speed: 10.0", "paddle_speed: 0.000001");
+        let err = parse_config(too_slow.as_bytes()).unwrap_err();
+        assert_eq!(
+            err,
+            "game.paddle_speed must be at least 1/65536, got 0.000001"
+        );
+    }
+}
@@ -0,0 +1,518 @@
+use bevy::{ecs::system::SystemParam, input::touch::Touch, prelude::*, window::CursorMoved};
+#[cfg(feature = "online")]
+use bevy_ggrs::PlayerInputs;
+
+#[cfg(feature = "online")]
+use crate::online::GGRSConfig;
+use crate::{
+    fixed::Fixed,
+    local::{AiController, LocalConfig},
+    pong::{
+        Ball, Collider, Paddle, PaddleInput, PaddleInputs, PongConfig, PongState, Position, Side,
+    },
+};
+
+/// Furthest a finger can move in logical pixels and still tap rather than drag
+const TAP_DISTANCE: f32 = 20.;
+
+/// Where the input of a paddle comes from, read into its slot of `PaddleInputs` every tick by
+/// [`controller_input`]
+#[derive(Component)]
+pub(crate) enum PaddleController {
+    Player(PlayerControls),
+    Ai(AiController),
+    // only the tests play back recorded input and run scripts
+    #[cfg(test)]
+    Replay(Replay),
+    #[cfg(test)]
+    Script(Script),
+    /// Input of a player in an online match, taken from the rollback session. The session asks the
+    /// paddles of the players at this machine for the input it sends, which `local` reads.
+    #[cfg(feature = "online")]
+    Network {
+        local: Box<PaddleController>,
+    },
+}
+
+impl PaddleController {
+    /// Read the input of a paddle from its controller, `None` when there is nothing to go by: an
+    /// AI without a ball to follow, or a network player, whose input only the session knows
+    pub(crate) fn input(
+        &mut self,
+        (paddle, position, collider): (&Paddle, &Position, &Collider),
+        sources: &ControllerSources,
+    ) -> Option<PaddleInput> {
+        match self {
+            PaddleController::Player(controls) => {
+                let mut input = controls.input(&sources.keyboard, &sources.buttons, &sources.axes);
+                controls.follow_pointer(&sources.pointer, paddle, position, &mut input);
+                Some(input)
+            }
+            PaddleController::Ai(ai) => {
+                let ball = sources.ball_query.get_single().ok()?;
+                let handicap = sources.local_config.ai_handicaps.get(ai.difficulty);
+                Some(ai.input(
+                    handicap,
+                    (paddle, position, collider),
+                    ball,
+                    &sources.pong_state,
+                    &sources.pong_config,
+                ))
+            }
+            #[cfg(test)]
+            PaddleController::Replay(replay) => Some(replay.next_input()),
+            #[cfg(test)]
+            PaddleController::Script(script) => Some(script.next_input(paddle.handle)),
+            #[cfg(feature = "online")]
+            PaddleController::Network { .. } => None,
+        }
+    }
+}
+
+/// Input recorded earlier, played back one tick at a time. The paddle stands still once it runs
+/// out.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Replay {
+    inputs: Vec<PaddleInput>,
+    next: usize,
+}
+
+#[cfg(test)]
+impl Replay {
+    pub(crate) fn new(inputs: Vec<PaddleInput>) -> Self {
+        Replay { inputs, next: 0 }
+    }
+
+    fn next_input(&mut self) -> PaddleInput {
+        let input = self.inputs.get(self.next).copied().unwrap_or_default();
+        self.next += 1;
+        input
+    }
+}
+
+/// Input worked out from the number of ticks played so far and the handle of the paddle, the same
+/// every time for the same tick
+#[cfg(test)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Script {
+    script: fn(usize, usize) -> PaddleInput,
+    tick: usize,
+}
+
+#[cfg(test)]
+impl Script {
+    pub(crate) fn new(script: fn(usize, usize) -> PaddleInput) -> Self {
+        Script { script, tick: 0 }
+    }
+
+    fn next_input(&mut self, handle: usize) -> PaddleInput {
+        let input = (self.script)(self.tick, handle);
+        self.tick += 1;
+        input
+    }
+}
+
+/// Everything the controllers read the input of their paddle from
+#[derive(SystemParam)]
+pub(crate) struct ControllerSources<'w, 's> {
+    keyboard: Res<'w, Input<KeyCode>>,
+    buttons: Res<'w, Input<GamepadButton>>,
+    axes: Res<'w, Axis<GamepadAxis>>,
+    pointer: ResMut<'w, PointerInput>,
+    ball_query:
+        Query<'w, 's, (&'static Ball, &'static Position, &'static Collider), Without<Paddle>>,
+    pong_state: Res<'w, PongState>,
+    pong_config: Res<'w, PongConfig>,
+    local_config: Res<'w, LocalConfig>,
+}
+
+/// Keys moving a paddle, serving with it and pausing the match, any of the listed keys will do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct KeyboardScheme {
+    pub(crate) up: Vec<KeyCode>,
+    pub(crate) down: Vec<KeyCode>,
+    pub(crate) serve: Vec<KeyCode>,
+    pub(crate) pause: Vec<KeyCode>,
+}
+
+impl KeyboardScheme {
+    pub(crate) fn input(&self, keyboard: &Input<KeyCode>) -> PaddleInput {
+        PaddleInput {
+            axis: PaddleInput::digital_axis(
+                keyboard.any_pressed(self.up.iter().copied()),
+                keyboard.any_pressed(self.down.iter().copied()),
+            ),
+            serve: keyboard.any_pressed(self.serve.iter().copied()),
+        }
+    }
+}
+
+/// Where the mouse and the fingers on the screen point at, in world space
+#[derive(Resource, Debug, Default)]
+pub(crate) struct PointerInput {
+    /// The mouse cursor, followed from when it moves until a key or button is pressed
+    mouse: Option<Vec2>,
+    touches: Vec<Vec2>,
+    /// Clicks and taps since the last tick
+    taps: Vec<Vec2>,
+}
+
+/// World position of a point in the window, as seen by a camera with a centered projection scaled
+/// to the window size, which is what the court is drawn with
+fn window_to_world(
+    point: Vec2,
+    window_size: Vec2,
+    camera: &GlobalTransform,
+    projection: &OrthographicProjection,
+) -> Vec2 {
+    camera.translation().truncate() + (point - window_size / 2.) * projection.scale
+}
+
+/// Follow the mouse and the fingers on the screen, runs every frame so no tap is missed between
+/// ticks
+#[allow(clippy::type_complexity)]
+pub(crate) fn pointer_input(
+    mut pointer: ResMut<PointerInput>,
+    windows: Res<Windows>,
+    camera_query: Query<(&GlobalTransform, &OrthographicProjection), With<Camera>>,
+    (keyboard, buttons, mouse_buttons, touches): (
+        Res<Input<KeyCode>>,
+        Res<Input<GamepadButton>>,
+        Res<Input<MouseButton>>,
+        Res<Touches>,
+    ),
+    mut cursor_moved: EventReader<CursorMoved>,
+) {
+    let (Some(window), Ok((camera, projection))) =
+        (windows.get_primary(), camera_query.get_single())
+    else {
+        return;
+    };
+    let window_size = Vec2::new(window.width(), window.height());
+    let to_world = |point| window_to_world(point, window_size, camera, projection);
+    // touches are measured from the top of the window, the cursor from the bottom
+    let touch_to_world = |touch: &Touch| {
+        to_world(Vec2::new(
+            touch.position().x,
+            window_size.y - touch.position().y,
+        ))
+    };
+
+    let moved = cursor_moved.iter().count() > 0;
+    let pressed =
+        keyboard.get_just_pressed().next().is_some() || buttons.get_just_pressed().next().is_some();
+    let cursor = window.cursor_position();
+    pointer.mouse = match cursor {
+        Some(cursor) if !pressed && (moved || pointer.mouse.is_some()) => Some(to_world(cursor)),
+        _ => None,
+    };
+    if let Some(cursor) = cursor.filter(|_| mouse_buttons.just_pressed(MouseButton::Left)) {
+        pointer.taps.push(to_world(cursor));
+    }
+
+    pointer.touches = touches.iter().map(touch_to_world).collect();
+    let taps: Vec<Vec2> = touches
+        .iter_just_released()
+        .filter(|touch| touch.distance().length() <= TAP_DISTANCE)
+        .map(touch_to_world)
+        .collect();
+    pointer.taps.extend(taps);
+}
+
+pub(crate) fn reset_pointer_input(mut pointer: ResMut<PointerInput>) {
+    *pointer = PointerInput::default();
+}
+
+pub(crate) fn clear_pointer_taps(mut pointer: ResMut<PointerInput>) {
+    pointer.taps.clear();
+}
+
+/// Devices of a player at this machine, their keys and a gamepad once one is assigned to them
+#[derive(Debug, Clone)]
+pub(crate) struct PlayerControls {
+    pub(crate) keys: KeyboardScheme,
+    pub(crate) gamepad: Option<Gamepad>,
+    /// Half of the screen the mouse and touches of the player are read from, all of it when
+    /// `None`
+    pub(crate) zone: Option<Side>,
+}
+
+impl PlayerControls {
+    pub(crate) fn new(keys: KeyboardScheme) -> Self {
+        PlayerControls {
+            keys,
+            gamepad: None,
+            zone: None,
+        }
+    }
+
+    /// The keys and the D-pad move the paddle at full speed, the left stick as fast as it is
+    /// tilted
+    pub(crate) fn input(
+        &self,
+        keyboard: &Input<KeyCode>,
+        buttons: &Input<GamepadButton>,
+        axes: &Axis<GamepadAxis>,
+    ) -> PaddleInput {
+        let mut input = self.keys.input(keyboard);
+        let Some(gamepad) = self.gamepad else {
+            return input;
+        };
+
+        let pressed = |button_type| buttons.pressed(GamepadButton::new(gamepad, button_type));
+        input.serve |= pressed(GamepadButtonType::South);
+        if input.axis == 0 {
+            input.axis = PaddleInput::digital_axis(
+                pressed(GamepadButtonType::DPadUp),
+                pressed(GamepadButtonType::DPadDown),
+            );
+        }
+        if input.axis == 0 {
+            let stick = axes.get(GamepadAxis::new(gamepad, GamepadAxisType::LeftStickY));
+            input.axis = PaddleInput::analog_axis(stick.unwrap_or(0.));
+        }
+        input
+    }
+
+    fn in_zone(&self, point: Vec2) -> bool {
+        match self.zone {
+            None => true,
+            Some(Side::Left) => point.x < 0.,
+            Some(Side::Right) => point.x >= 0.,
+        }
+    }
+
+    /// Move the paddle towards a finger on its half of the screen, or else the mouse, unless the
+    /// keys or the gamepad already move it. The paddle slows down as it gets close, to stop right
+    /// under the pointer. A click or tap serves.
+    fn follow_pointer(
+        &self,
+        pointer: &PointerInput,
+        paddle: &Paddle,
+        position: &Position,
+        input: &mut PaddleInput,
+    ) {
+        input.serve |= pointer.taps.iter().any(|&tap| self.in_zone(tap));
+        if input.axis != 0 {
+            return;
+        }
+        let target = pointer
+            .touches
+            .iter()
+            .chain(&pointer.mouse)
+            .find(|&&point| self.in_zone(point));
+        let Some(target) = target else {
+            return;
+        };
+        let ticks_away = (Fixed::from_f32(target.y) - position.y) / paddle.speed;
+        input.axis = PaddleInput::analog_axis(ticks_away.to_f32());
+    }
+
+    /// Whether the player asked to pause or resume the match, with a key or the Start button
+    pub(crate) fn pause_just_pressed(
+        &self,
+        keyboard: &Input<KeyCode>,
+        buttons: &Input<GamepadButton>,
+    ) -> bool {
+        let start = match self.gamepad {
+            Some(gamepad) => {
+                buttons.just_pressed(GamepadButton::new(gamepad, GamepadButtonType::Start))
+            }
+            None => false,
+        };
+        keyboard.any_just_pressed(self.keys.pause.iter().copied()) || start
+    }
+}
+
+/// Fill the input of every paddle from its controller, in the slot given by its handle
+pub(crate) fn controller_input(
+    mut paddle_query: Query<(&Paddle, &Position, &Collider, &mut PaddleController)>,
+    mut inputs: ResMut<PaddleInputs>,
+    mut sources: ControllerSources,
+    #[cfg(feature = "online")] network_inputs: Option<Res<PlayerInputs<GGRSConfig>>>,
+) {
+    for (paddle, position, collider, mut controller) in &mut paddle_query {
+        let input = match &mut *controller {
+            #[cfg(feature = "online")]
+            PaddleController::Network { .. } => network_inputs
+                .as_ref()
+                .map(|network_inputs| network_inputs[paddle.handle].0.into()),
+            controller => controller.input((paddle, position, collider), &sources),
+        };
+        if let Some(input) = input {
+            inputs[paddle.handle] = input;
+        }
+    }
+    sources.pointer.taps.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        controls::KeyBindings,
+        fixed::FixedVec2,
+        pong::{tests::simulation_app, GameloopStage, LeftPlayer, Score, Side},
+    };
+
+    #[test]
+    fn controllers_fill_the_slot_of_their_paddle() {
+        let mut app = simulation_app();
+        app.init_resource::<LocalConfig>()
+            .init_resource::<Input<KeyCode>>()
+            .init_resource::<Input<GamepadButton>>()
+            .init_resource::<Axis<GamepadAxis>>()
+            .init_resource::<PointerInput>()
+            .add_system(controller_input.before(GameloopStage::Input));
+        app.update();
+
+        // the paddles swapped handles, as they do in online rematches
+        let mut paddles = app.world.query::<(&mut Paddle, Option<&LeftPlayer>)>();
+        let mut handles = (0, 0);
+        for (mut paddle, left) in paddles.iter_mut(&mut app.world) {
+            paddle.handle = 1 - paddle.handle;
+            match left {
+                Some(_) => handles.0 = paddle.handle,
+                None => handles.1 = paddle.handle,
+            }
+        }
+        let mut paddles = app
+            .world
+            .query_filtered::<(Entity, Option<&LeftPlayer>), With<Paddle>>();
+        let controllers: Vec<(Entity, PaddleController)> = paddles
+            .iter(&app.world)
+            .map(|(paddle, left)| {
+                let side = match left {
+                    Some(_) => Side::Left,
+                    None => Side::Right,
+                };
+                let scheme = KeyBindings::default().scheme(side);
+                (
+                    paddle,
+                    PaddleController::Player(PlayerControls::new(scheme)),
+                )
+            })
+            .collect();
+        for (paddle, controller) in controllers {
+            app.world.entity_mut(paddle).insert(controller);
+        }
+
+        let mut keyboard = app.world.resource_mut::<Input<KeyCode>>();
+        keyboard.press(KeyCode::W);
+        keyboard.press(KeyCode::Down);
+        app.update();
+        let inputs = app.world.resource::<PaddleInputs>();
+        let (left, right) = (inputs[handles.0], inputs[handles.1]);
+        assert_eq!(left.axis, PaddleInput::AXIS_MAX);
+        assert_eq!(right.axis, -PaddleInput::AXIS_MAX);
+    }
+
+    /// App running a local match between the paddles with the given controllers
+    fn controlled_app(controller: impl Fn() -> PaddleController) -> App {
+        let mut app = simulation_app();
+        app.init_resource::<LocalConfig>()
+            .init_resource::<Input<KeyCode>>()
+            .init_resource::<Input<GamepadButton>>()
+            .init_resource::<Axis<GamepadAxis>>()
+            .init_resource::<PointerInput>()
+            .add_system(controller_input.before(GameloopStage::Input));
+        app.update();
+        let mut controllers = app.world.query_filtered::<Entity, With<Paddle>>();
+        let paddles: Vec<Entity> = controllers.iter(&app.world).collect();
+        for paddle in paddles {
+            app.world.entity_mut(paddle).insert(controller());
+        }
+        app
+    }
+
+    #[test]
+    fn replays_play_a_match_again() {
+        let script = |tick: usize, handle: usize| PaddleInput {
+            axis: if (tick / 40 + handle) % 2 == 0 {
+                PaddleInput::AXIS_MAX
+            } else {
+                -PaddleInput::AXIS_MAX
+            },
+            serve: tick % 50 == 0,
+        };
+        let mut scripted = controlled_app(|| PaddleController::Script(Script::new(script)));
+        let mut recording = vec![Vec::new(); 2];
+        for _ in 0..2_000 {
+            scripted.update();
+            for (handle, inputs) in recording.iter_mut().enumerate() {
+                inputs.push(scripted.world.resource::<PaddleInputs>()[handle]);
+            }
+        }
+
+        let mut replayed = controlled_app(|| PaddleController::Replay(Replay::default()));
+        let mut controllers = replayed.world.query::<(&Paddle, &mut PaddleController)>();
+        for (paddle, mut controller) in controllers.iter_mut(&mut replayed.world) {
+            *controller = PaddleController::Replay(Replay::new(recording[paddle.handle].clone()));
+        }
+        for _ in 0..2_000 {
+            replayed.update();
+        }
+
+        let positions = |app: &mut App| {
+            let mut positions = app.world.query::<&Position>();
+            let mut positions: Vec<FixedVec2> = positions
+                .iter(&app.world)
+                .map(|position| **position)
+                .collect();
+            positions.sort();
+            positions
+        };
+        assert_eq!(positions(&mut replayed), positions(&mut scripted));
+        let score = |app: &App| {
+            let score = app.world.resource::<Score>();
+            (score.left, score.right)
+        };
+        assert_eq!(score(&replayed), score(&scripted));
+    }
+
+    #[test]
+    fn pointers_steer_the_paddle_on_their_half_of_the_screen() {
+        let camera = GlobalTransform::from_translation(Vec3::new(0., 10., 0.));
+        let projection = OrthographicProjection {
+            scale: 2.,
+            ..default()
+        };
+        let window = Vec2::new(800., 600.);
+        let to_world = |point| window_to_world(point, window, &camera, &projection);
+        assert_eq!(to_world(Vec2::new(400., 300.)), Vec2::new(0., 10.));
+        assert_eq!(to_world(Vec2::new(0., 600.)), Vec2::new(-800., 610.));
+
+        let mut controls = PlayerControls::new(KeyBindings::default().scheme(Side::Right));
+        controls.zone = Some(Side::Right);
+        let paddle = Paddle {
+            handle: 1,
+            speed: Fixed::from_f32(10.),
+            direction: FixedVec2::ZERO,
+            velocity: Fixed::ZERO,
+        };
+        let position = Position(FixedVec2::new(Fixed::from_f32(640.), Fixed::ZERO));
+        let follow = |pointer: &PointerInput| {
+            let mut input = PaddleInput::default();
+            controls.follow_pointer(pointer, &paddle, &position, &mut input);
+            input
+        };
+
+        // the left half of the screen belongs to the other player
+        let mut pointer = PointerInput {
+            mouse: Some(Vec2::new(300., -200.)),
+            touches: vec![Vec2::new(-300., 200.)],
+            taps: vec![Vec2::new(-10., 0.)],
+        };
+        let input = follow(&pointer);
+        assert_eq!(input.axis, -PaddleInput::AXIS_MAX);
+        assert!(!input.serve);
+
+        // a finger wins over the mouse, and the paddle slows down half a tick away from it
+        pointer.touches.push(Vec2::new(500., 5.));
+        pointer.taps.push(Vec2::new(10., 0.));
+        let input = follow(&pointer);
+        assert_eq!(input.axis, 64);
+        assert!(input.serve);
+    }
+}
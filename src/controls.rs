@@ -0,0 +1,578 @@
+use bevy::prelude::*;
+use iyes_loopless::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{controller::KeyboardScheme, pong::Side, types::GameState, util::despawn_with};
+
+/// Keys that leave the menus and the matches, and resume or restart a match, they can't be bound
+/// to an action
+const RESERVED_KEYS: [KeyCode; 2] = [KeyCode::Escape, KeyCode::Return];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    Up,
+    Down,
+    Serve,
+    Pause,
+}
+
+impl Action {
+    const ALL: [Action; 4] = [Action::Up, Action::Down, Action::Serve, Action::Pause];
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Action::Up => "Up",
+            Action::Down => "Down",
+            Action::Serve => "Serve",
+            Action::Pause => "Pause",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Keys of the actions of one player
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PlayerKeys {
+    pub(crate) up: KeyCode,
+    pub(crate) down: KeyCode,
+    pub(crate) serve: KeyCode,
+    pub(crate) pause: KeyCode,
+}
+
+impl PlayerKeys {
+    fn get(&self, action: Action) -> KeyCode {
+        match action {
+            Action::Up => self.up,
+            Action::Down => self.down,
+            Action::Serve => self.serve,
+            Action::Pause => self.pause,
+        }
+    }
+
+    fn get_mut(&mut self, action: Action) -> &mut KeyCode {
+        match action {
+            Action::Up => &mut self.up,
+            Action::Down => &mut self.down,
+            Action::Serve => &mut self.serve,
+            Action::Pause => &mut self.pause,
+        }
+    }
+}
+
+/// Why a key can't be bound to an action
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BindingError {
+    Reserved,
+    /// Already bound to another action, of this or the other player
+    Conflict(Side, Action),
+}
+
+/// Keys of both players at this keyboard, rebound on the controls screen and kept between runs
+#[derive(Resource, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct KeyBindings {
+    pub(crate) left: PlayerKeys,
+    pub(crate) right: PlayerKeys,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            left: PlayerKeys {
+                up: KeyCode::W,
+                down: KeyCode::S,
+                serve: KeyCode::Space,
+                pause: KeyCode::Tab,
+            },
+            right: PlayerKeys {
+                up: KeyCode::Up,
+                down: KeyCode::Down,
+                serve: KeyCode::RShift,
+                pause: KeyCode::Back,
+            },
+        }
+    }
+}
+
+impl KeyBindings {
+    pub(crate) fn player(&self, side: Side) -> &PlayerKeys {
+        match side {
+            Side::Left => &self.left,
+            Side::Right => &self.right,
+        }
+    }
+
+    /// Keys of the player on a side, when each player has their own
+    pub(crate) fn scheme(&self, side: Side) -> KeyboardScheme {
+        let keys = self.player(side);
+        KeyboardScheme {
+            up: vec![keys.up],
+            down: vec![keys.down],
+            serve: vec![keys.serve],
+            pause: vec![keys.pause],
+        }
+    }
+
+    /// Keys of both players, for a player who has the keyboard to themselves
+    pub(crate) fn either(&self) -> KeyboardScheme {
+        let (left, right) = (&self.left, &self.right);
+        KeyboardScheme {
+            up: vec![left.up, right.up],
+            down: vec![left.down, right.down],
+            serve: vec![left.serve, right.serve],
+            pause: vec![left.pause, right.pause],
+        }
+    }
+
+    /// The action a key is bound to, other than the given one
+    fn bound_elsewhere(&self, side: Side, action: Action, key: KeyCode) -> Option<(Side, Action)> {
+        [Side::Left, Side::Right]
+            .into_iter()
+            .flat_map(|other_side| Action::ALL.map(|other_action| (other_side, other_action)))
+            .filter(|&binding| binding != (side, action))
+            .find(|&(other_side, other_action)| self.player(other_side).get(other_action) == key)
+    }
+
+    pub(crate) fn bind(
+        &mut self,
+        side: Side,
+        action: Action,
+        key: KeyCode,
+    ) -> Result<(), BindingError> {
+        if RESERVED_KEYS.contains(&key) {
+            return Err(BindingError::Reserved);
+        }
+        if let Some((other_side, other_action)) = self.bound_elsewhere(side, action, key) {
+            return Err(BindingError::Conflict(other_side, other_action));
+        }
+        let keys = match side {
+            Side::Left => &mut self.left,
+            Side::Right => &mut self.right,
+        };
+        *keys.get_mut(action) = key;
+        Ok(())
+    }
+
+    /// Everything wrong with a set of bindings read back from storage
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for side in [Side::Left, Side::Right] {
+            for action in Action::ALL {
+                let key = self.player(side).get(action);
+                if RESERVED_KEYS.contains(&key) {
+                    errors.push(format!("{key:?} can't be bound to {side:?} {action}"));
+                } else if let Some((other_side, other_action)) =
+                    self.bound_elsewhere(side, action, key)
+                {
+                    errors.push(format!(
+                        "{key:?} is bound to both {side:?} {action} and {other_side:?} {other_action}"
+                    ));
+                }
+            }
+        }
+        errors
+    }
+}
+
+fn parse_key_bindings(text: &str) -> Result<KeyBindings, String> {
+    let bindings: KeyBindings = ron::from_str(text).map_err(|err| err.to_string())?;
+    let errors = bindings.validate();
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(bindings)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod storage {
+    use std::{env, fs, path::PathBuf};
+
+    /// Where the key bindings are kept, in the config directory of the user
+    fn path() -> Option<PathBuf> {
+        let config_dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_dir.join("pong").join("controls.ron"))
+    }
+
+    pub(super) fn read() -> Result<Option<String>, String> {
+        let Some(path) = path() else {
+            return Ok(None);
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => Ok(Some(text)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(format!("{}: {err}", path.display())),
+        }
+    }
+
+    pub(super) fn write(text: &str) -> Result<(), String> {
+        let path = path().ok_or("no config directory to save the controls in")?;
+        let write = || {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(&path, text)
+        };
+        write().map_err(|err| format!("{}: {err}", path.display()))
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod storage {
+    /// Key of the key bindings in the local storage of the page
+    const STORAGE_KEY: &str = "pong.controls";
+
+    fn local_storage() -> Result<web_sys::Storage, String> {
+        web_sys::window()
+            .and_then(|window| window.local_storage().ok().flatten())
+            .ok_or_else(|| "local storage is not available".to_string())
+    }
+
+    pub(super) fn read() -> Result<Option<String>, String> {
+        local_storage()?
+            .get_item(STORAGE_KEY)
+            .map_err(|err| format!("{err:?}"))
+    }
+
+    pub(super) fn write(text: &str) -> Result<(), String> {
+        local_storage()?
+            .set_item(STORAGE_KEY, text)
+            .map_err(|err| format!("{err:?}"))
+    }
+}
+
+/// The saved key bindings, or the default ones when none were saved or they can't be used
+fn load_key_bindings() -> KeyBindings {
+    match storage::read().and_then(|text| text.map(|text| parse_key_bindings(&text)).transpose()) {
+        Ok(bindings) => bindings.unwrap_or_default(),
+        Err(err) => {
+            warn!("invalid saved controls, using the default ones:\n{err}");
+            KeyBindings::default()
+        }
+    }
+}
+
+fn save_key_bindings(bindings: Res<KeyBindings>) {
+    let saved = ron::ser::to_string_pretty(&*bindings, default())
+        .map_err(|err| err.to_string())
+        .and_then(|text| storage::write(&text));
+    if let Err(err) = saved {
+        warn!("failed to save the controls: {err}");
+    }
+}
+
+/// Main menu button that opens the controls screen
+#[derive(Component)]
+pub(crate) struct ControlsButton;
+
+#[derive(Component)]
+struct ControlsUI;
+
+/// Waits for a key to bind to its action when clicked
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+struct BindingButton {
+    side: Side,
+    action: Action,
+}
+
+#[derive(Component)]
+struct BindingText(BindingButton);
+
+#[derive(Component)]
+struct DefaultsButton;
+
+#[derive(Component)]
+struct ControlsMessage;
+
+/// The binding waiting for a key, and what became of the last one
+#[derive(Resource, Default)]
+struct ControlsScreen {
+    listening: Option<BindingButton>,
+    message: String,
+}
+
+fn binding_label(
+    binding: BindingButton,
+    bindings: &KeyBindings,
+    screen: &ControlsScreen,
+) -> String {
+    if screen.listening == Some(binding) {
+        "...".to_string()
+    } else {
+        format!("{:?}", bindings.player(binding.side).get(binding.action))
+    }
+}
+
+fn setup_controls(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    bindings: Res<KeyBindings>,
+) {
+    let screen = ControlsScreen::default();
+    let font = asset_server.load("fonts/PublicPixel-z84yD.ttf");
+    let text_style = TextStyle {
+        font: font.clone(),
+        font_size: 24.,
+        ..default()
+    };
+    let small_text_style = TextStyle {
+        font,
+        font_size: 16.,
+        ..default()
+    };
+    let cell_style = Style {
+        size: Size::new(Val::Px(240.), Val::Px(40.)),
+        margin: UiRect::all(Val::Px(10.)),
+        justify_content: JustifyContent::Center,
+        align_items: AlignItems::Center,
+        ..default()
+    };
+    let button_bundle = ButtonBundle {
+        style: cell_style.clone(),
+        background_color: Color::BLACK.into(),
+        ..default()
+    };
+    let row_bundle = NodeBundle {
+        style: Style {
+            align_items: AlignItems::Center,
+            ..default()
+        },
+        ..default()
+    };
+
+    commands
+        .spawn((
+            ControlsUI,
+            NodeBundle {
+                style: Style {
+                    size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                    flex_direction: FlexDirection::ColumnReverse,
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn(row_bundle.clone()).with_children(|parent| {
+                for header in ["", "Left", "Right"] {
+                    parent.spawn(
+                        TextBundle::from_section(header, text_style.clone())
+                            .with_style(cell_style.clone()),
+                    );
+                }
+            });
+
+            for action in Action::ALL {
+                parent.spawn(row_bundle.clone()).with_children(|parent| {
+                    parent.spawn(
+                        TextBundle::from_section(action.to_string(), text_style.clone())
+                            .with_style(cell_style.clone()),
+                    );
+                    for side in [Side::Left, Side::Right] {
+                        let binding = BindingButton { side, action };
+                        parent
+                            .spawn((binding, button_bundle.clone()))
+                            .with_children(|parent| {
+                                parent.spawn((
+                                    BindingText(binding),
+                                    TextBundle::from_section(
+                                        binding_label(binding, &bindings, &screen),
+                                        text_style.clone(),
+                                    ),
+                                ));
+                            });
+                    }
+                });
+            }
+
+            parent
+                .spawn((DefaultsButton, button_bundle))
+                .with_children(|parent| {
+                    parent.spawn(TextBundle::from_section("Defaults", text_style.clone()));
+                });
+
+            parent.spawn((
+                ControlsMessage,
+                TextBundle::from_section("", small_text_style.clone())
+                    .with_text_alignment(TextAlignment::CENTER)
+                    .with_style(Style {
+                        margin: UiRect::all(Val::Px(10.)),
+                        ..default()
+                    }),
+            ));
+
+            parent.spawn(
+                TextBundle::from_section(
+                    "Click an action and press its new key\n\nEscape to go back",
+                    small_text_style,
+                )
+                .with_text_alignment(TextAlignment::CENTER)
+                .with_style(Style {
+                    margin: UiRect::all(Val::Px(20.)),
+                    ..default()
+                }),
+            );
+        });
+    commands.insert_resource(screen);
+}
+
+fn open_controls(
+    mut commands: Commands,
+    interaction_query: Query<&Interaction, (Changed<Interaction>, With<ControlsButton>)>,
+) {
+    if interaction_query
+        .iter()
+        .any(|interaction| *interaction == Interaction::Clicked)
+    {
+        commands.insert_resource(NextState(GameState::Controls));
+    }
+}
+
+fn controls_buttons(
+    mut bindings: ResMut<KeyBindings>,
+    mut screen: ResMut<ControlsScreen>,
+    binding_query: Query<(&Interaction, &BindingButton), Changed<Interaction>>,
+    defaults_query: Query<&Interaction, (Changed<Interaction>, With<DefaultsButton>)>,
+) {
+    for (interaction, binding) in &binding_query {
+        if *interaction == Interaction::Clicked {
+            screen.listening = Some(*binding);
+            screen.message = format!(
+                "Press a key for {:?} {}, Escape to cancel",
+                binding.side, binding.action
+            );
+        }
+    }
+    if defaults_query
+        .iter()
+        .any(|interaction| *interaction == Interaction::Clicked)
+    {
+        *bindings = KeyBindings::default();
+        screen.listening = None;
+        screen.message.clear();
+    }
+}
+
+/// Bind the next key pressed to the action waiting for one, or go back to the menu on Escape
+fn controls_keys(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut bindings: ResMut<KeyBindings>,
+    mut screen: ResMut<ControlsScreen>,
+) {
+    let Some(&key) = keyboard_input.get_just_pressed().next() else {
+        return;
+    };
+    let Some(binding) = screen.listening else {
+        if key == KeyCode::Escape {
+            commands.insert_resource(NextState(GameState::MainMenu));
+        }
+        return;
+    };
+
+    if key == KeyCode::Escape {
+        screen.listening = None;
+        screen.message.clear();
+        return;
+    }
+    match bindings.bind(binding.side, binding.action, key) {
+        Ok(()) => {
+            screen.listening = None;
+            screen.message.clear();
+        }
+        Err(BindingError::Reserved) => {
+            screen.message = format!("{key:?} can't be bound, pick another key");
+        }
+        Err(BindingError::Conflict(side, action)) => {
+            screen.message = format!("{key:?} is already {side:?} {action}, pick another key");
+        }
+    }
+}
+
+fn controls_texts(
+    bindings: Res<KeyBindings>,
+    screen: Res<ControlsScreen>,
+    mut binding_query: Query<(&mut Text, &BindingText), Without<ControlsMessage>>,
+    mut message_query: Query<&mut Text, With<ControlsMessage>>,
+) {
+    if !bindings.is_changed() && !screen.is_changed() {
+        return;
+    }
+    for (mut text, binding) in &mut binding_query {
+        text.sections[0].value = binding_label(binding.0, &bindings, &screen);
+    }
+    for mut text in &mut message_query {
+        text.sections[0].value = screen.message.clone();
+    }
+}
+
+/// Rebinding the keys of the players, opened from the main menu
+pub(crate) struct ControlsPlugin;
+
+impl Plugin for ControlsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(load_key_bindings())
+            .add_enter_system(GameState::Controls, setup_controls)
+            .add_exit_system(GameState::Controls, despawn_with::<ControlsUI>)
+            .add_exit_system(GameState::Controls, save_key_bindings)
+            .add_system(open_controls.run_in_state(GameState::MainMenu))
+            .add_system_set(
+                ConditionSet::new()
+                    .run_in_state(GameState::Controls)
+                    .with_system(controls_buttons)
+                    .with_system(controls_keys)
+                    .with_system(controls_texts)
+                    .into(),
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bindings_refuse_keys_in_use() {
+        let mut bindings = KeyBindings::default();
+        assert_eq!(
+            bindings.bind(Side::Right, Action::Serve, KeyCode::Space),
+            Err(BindingError::Conflict(Side::Left, Action::Serve))
+        );
+        for key in [KeyCode::Escape, KeyCode::Return] {
+            assert_eq!(
+                bindings.bind(Side::Left, Action::Pause, key),
+                Err(BindingError::Reserved)
+            );
+        }
+        // binding an action to its own key again is fine
+        assert_eq!(bindings.bind(Side::Left, Action::Up, KeyCode::W), Ok(()));
+        assert_eq!(
+            bindings.bind(Side::Right, Action::Serve, KeyCode::RControl),
+            Ok(())
+        );
+        assert_eq!(bindings.right.serve, KeyCode::RControl);
+        assert_eq!(bindings.either().serve, [KeyCode::Space, KeyCode::RControl]);
+    }
+
+    #[test]
+    fn saved_bindings_are_read_back() {
+        let mut bindings = KeyBindings::default();
+        bindings.left.up = KeyCode::Q;
+        let text = ron::ser::to_string_pretty(&bindings, default()).unwrap();
+        assert_eq!(parse_key_bindings(&text), Ok(bindings));
+
+        let conflicting = text.replace("Q", "S");
+        let err = parse_key_bindings(&conflicting).unwrap_err();
+        assert!(
+            err.starts_with("S is bound to both Left Up and Left Down"),
+            "{err}"
+        );
+    }
+}
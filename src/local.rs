@@ -1,185 +1,617 @@
 use std::time::Duration;
 
-use bevy::prelude::*;
+use bevy::{input::InputSystem, prelude::*};
 use iyes_loopless::prelude::*;
+use rand::Rng;
+use serde::Deserialize;
 
 use crate::{
+    controller::{
+        clear_pointer_taps, controller_input, pointer_input, reset_pointer_input, PaddleController,
+        PlayerControls, PointerInput,
+    },
+    controls::KeyBindings,
+    fixed::{Fixed, FixedVec2},
     pong::{
-        ball_collision, ball_movement, ball_scoring, paddle_movement, scoreboard, serve, Ball,
-        GameloopStage, LeftPlayer, Paddle, PaddleInputs, PongConfig, PongState, RightPlayer, Side,
+        ball_collision, ball_scoring, paddle_movement, reset_court, reset_match_state, scoreboard,
+        serve, update_match_state, Ball, Collider, GameloopStage, LeftPlayer, Paddle, PaddleInput,
+        PongConfig, PongState, Position, RightPlayer, Side, Timestep,
     },
-    types::GameState,
-    GameType,
+    types::{GameState, GameType},
+    util::despawn_with,
 };
 
-#[derive(Component)]
-struct PlayerController;
-
-#[derive(Component)]
-struct AiController;
+/// Length of a tick of local matches
+pub(crate) const TIMESTEP: Duration = Duration::from_millis(30);
 
 #[derive(Component, Debug, Clone, Eq, PartialEq, Hash)]
 pub(crate) enum LocalGameType {
     SinglePlayer,
     MultiPlayer,
+    /// AI against AI behind the main menu
+    Demo,
 }
 
-struct AiHandicap {
-    view_percentage: f32,
+impl LocalGameType {
+    /// Sides of the court played by the AI, the others are played on the keyboard
+    fn ai_sides(&self) -> &'static [Side] {
+        match self {
+            LocalGameType::SinglePlayer => &[Side::Right],
+            LocalGameType::MultiPlayer => &[],
+            LocalGameType::Demo => &[Side::Left, Side::Right],
+        }
+    }
 }
 
-#[derive(Resource)]
-struct LocalConfig {
-    ai_handicap: AiHandicap,
+/// How well the AI plays single player matches, picked in the main menu
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AiDifficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+    Impossible,
 }
 
-fn ai_input(
-    mut paddle_query: Query<(&mut Paddle, &Transform, &Sprite), With<AiController>>,
-    ball_query: Query<(&Ball, &Transform), Without<Paddle>>,
-    mut inputs: ResMut<PaddleInputs>,
-    pong_config: Res<PongConfig>,
-    local_config: Res<LocalConfig>,
-) {
-    let court_width = pong_config.court_size[0];
-    for (mut paddle, paddle_transform, paddle_sprite) in &mut paddle_query {
-        let paddle_size = paddle_sprite
-            .custom_size
-            .expect("Paddle should have custom size");
-        let view_distance_px = court_width * local_config.ai_handicap.view_percentage;
-        let mut direction = 0.0;
-        let (ball, ball_transform) = ball_query.single();
-        if ball.velocity.x < 0.0
-            || (ball_transform.translation.x - paddle_transform.translation.x).abs()
-                > view_distance_px
-        {
-            return;
-        }
-
-        if (ball_transform.translation.y - paddle_transform.translation.y).abs()
-            > paddle_size.y / 2.
-        {
-            let input = &mut inputs[1];
-            input.move_down = false;
-            input.move_up = false;
-            input.serve = true;
-            if ball_transform.translation.y > paddle_transform.translation.y {
-                input.move_up = true;
-            }
-            if ball_transform.translation.y < paddle_transform.translation.y {
-                input.move_down = true;
-            }
-        }
+impl AiDifficulty {
+    const ALL: [AiDifficulty; 4] = [
+        AiDifficulty::Easy,
+        AiDifficulty::Normal,
+        AiDifficulty::Hard,
+        AiDifficulty::Impossible,
+    ];
 
-        paddle.direction.y = direction;
+    /// Next difficulty in the order easy to impossible, wrapping around at both ends
+    pub(crate) fn step(self, step: i32) -> Self {
+        let index = Self::ALL.iter().position(|&level| level == self).unwrap() as i32;
+        Self::ALL[(index + step).rem_euclid(Self::ALL.len() as i32) as usize]
     }
 }
 
-fn keyboard_input(
-    keyboard_input: Res<Input<KeyCode>>,
-    mut inputs: ResMut<PaddleInputs>,
-    local_game_type: Res<CurrentState<LocalGameType>>,
-) {
-    inputs[0].move_down = false;
-    inputs[0].move_up = false;
-    inputs[0].serve = false;
-
-    if keyboard_input.pressed(KeyCode::W) {
-        inputs[0].move_up = true;
+impl std::fmt::Display for AiDifficulty {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            AiDifficulty::Easy => "Easy",
+            AiDifficulty::Normal => "Normal",
+            AiDifficulty::Hard => "Hard",
+            AiDifficulty::Impossible => "Impossible",
+        };
+        f.write_str(name)
     }
-    if keyboard_input.pressed(KeyCode::S) {
-        inputs[0].move_down = true;
+}
+
+/// Limits on how well the AI plays at one difficulty
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct AiHandicap {
+    /// How far the AI sees the ball coming, as a fraction of the court width
+    pub(crate) view_percentage: f32,
+    /// Ticks the AI takes to react to the ball turning or a serve it has to make
+    pub(crate) reaction_ticks: u32,
+    /// Furthest the AI aims off the point it predicts the ball to arrive at, in court units
+    pub(crate) aim_error: f32,
+    /// Fraction of the paddle speed the AI moves its paddle at
+    pub(crate) tracking_speed: f32,
+}
+
+/// The AI handicap of every difficulty
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct AiHandicaps {
+    pub(crate) easy: AiHandicap,
+    pub(crate) normal: AiHandicap,
+    pub(crate) hard: AiHandicap,
+    pub(crate) impossible: AiHandicap,
+}
+
+impl AiHandicaps {
+    pub(crate) fn get(&self, difficulty: AiDifficulty) -> &AiHandicap {
+        match difficulty {
+            AiDifficulty::Easy => &self.easy,
+            AiDifficulty::Normal => &self.normal,
+            AiDifficulty::Hard => &self.hard,
+            AiDifficulty::Impossible => &self.impossible,
+        }
     }
-    if keyboard_input.pressed(KeyCode::Space) {
-        inputs[0].serve = true;
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (AiDifficulty, &AiHandicap)> {
+        AiDifficulty::ALL
+            .into_iter()
+            .map(|difficulty| (difficulty, self.get(difficulty)))
     }
+}
 
-    if local_game_type.0 == LocalGameType::SinglePlayer {
-        if keyboard_input.pressed(KeyCode::Up) {
-            inputs[0].move_up = true;
-        }
-        if keyboard_input.pressed(KeyCode::Down) {
-            inputs[0].move_down = true;
+/// Tunables of local matches, loaded together with the `PongConfig`
+#[derive(Resource, Debug, Clone, PartialEq)]
+pub(crate) struct LocalConfig {
+    pub(crate) ai_handicaps: AiHandicaps,
+}
+
+impl Default for LocalConfig {
+    fn default() -> Self {
+        LocalConfig {
+            ai_handicaps: AiHandicaps {
+                easy: AiHandicap {
+                    view_percentage: 0.4,
+                    reaction_ticks: 12,
+                    aim_error: 60.,
+                    tracking_speed: 0.6,
+                },
+                normal: AiHandicap {
+                    view_percentage: 0.6,
+                    reaction_ticks: 7,
+                    aim_error: 40.,
+                    tracking_speed: 0.8,
+                },
+                hard: AiHandicap {
+                    view_percentage: 0.8,
+                    reaction_ticks: 3,
+                    aim_error: 20.,
+                    tracking_speed: 1.,
+                },
+                impossible: AiHandicap {
+                    view_percentage: 1.,
+                    reaction_ticks: 0,
+                    aim_error: 0.,
+                    tracking_speed: 1.,
+                },
+            },
         }
     }
+}
 
-    if local_game_type.0 == LocalGameType::MultiPlayer {
-        inputs[1].move_down = false;
-        inputs[1].move_up = false;
-        inputs[1].serve = false;
+/// What the AI last reacted to, it takes a moment to react again when this changes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum AiSituation {
+    #[default]
+    BallAway,
+    BallIncoming,
+    Serving,
+}
 
-        if keyboard_input.pressed(KeyCode::Up) {
-            inputs[1].move_up = true;
+/// The AI playing a paddle at a difficulty, with the state it keeps between ticks
+#[derive(Debug, Default)]
+pub(crate) struct AiController {
+    pub(crate) difficulty: AiDifficulty,
+    situation: AiSituation,
+    /// Ticks left before the AI reacts to its situation
+    reaction_ticks: u32,
+    /// How far the AI aims off this time around
+    aim_offset: Fixed,
+}
+
+/// Height at which a ball at `position` moving at `velocity` crosses `x`, bouncing off the court
+/// walls on the way. `reach` is the furthest the center of the ball gets from the center line.
+fn predict_intercept(position: FixedVec2, velocity: FixedVec2, x: Fixed, reach: Fixed) -> Fixed {
+    if velocity.x.is_zero() {
+        return position.y;
+    }
+    let ticks = (x - position.x) / velocity.x;
+    let y = position.y + velocity.y * ticks;
+
+    // unfold the bounces, the ball goes up and down the court once in a period
+    let span = reach + reach;
+    let y = (y + reach).rem_euclid(span + span);
+    let y = if y > span { span + span - y } else { y };
+    y - reach
+}
+
+impl AiController {
+    pub(crate) fn new(difficulty: AiDifficulty) -> Self {
+        AiController {
+            difficulty,
+            ..default()
         }
-        if keyboard_input.pressed(KeyCode::Down) {
-            inputs[1].move_down = true;
+    }
+
+    /// Input of the AI for the paddle it plays this tick
+    pub(crate) fn input(
+        &mut self,
+        handicap: &AiHandicap,
+        (paddle, paddle_position, paddle_collider): (&Paddle, &Position, &Collider),
+        (ball, ball_position, ball_collider): (&Ball, &Position, &Collider),
+        pong_state: &PongState,
+        pong_config: &PongConfig,
+    ) -> PaddleInput {
+        let mut input = PaddleInput::default();
+        let half = Fixed::from_int(2);
+        let side = if paddle_position.x < Fixed::ZERO {
+            Side::Left
+        } else {
+            Side::Right
+        };
+        let incoming =
+            (ball.velocity.x < Fixed::ZERO) == (side == Side::Left) && !ball.velocity.x.is_zero();
+        let view_distance = Fixed::from_f32(pong_config.court_size[0] * handicap.view_percentage);
+
+        let situation = match *pong_state {
+            PongState::Serve(server) if server == side => AiSituation::Serving,
+            PongState::Playing
+                if incoming && (ball_position.x - paddle_position.x).abs() <= view_distance =>
+            {
+                AiSituation::BallIncoming
+            }
+            _ => AiSituation::BallAway,
+        };
+        if situation != self.situation {
+            self.situation = situation;
+            self.reaction_ticks = handicap.reaction_ticks;
+            self.aim_offset = Fixed::from_f32(
+                rand::thread_rng().gen_range(-handicap.aim_error..=handicap.aim_error),
+            );
         }
-        if keyboard_input.pressed(KeyCode::Space) {
-            inputs[1].serve = true;
+
+        if self.reaction_ticks > 0 {
+            self.reaction_ticks -= 1;
+            return input;
         }
+
+        let target = match self.situation {
+            AiSituation::Serving => {
+                input.serve = true;
+                return input;
+            }
+            AiSituation::BallIncoming => {
+                let reach =
+                    (Fixed::from_f32(pong_config.court_size[1]) - ball_collider.size.y) / half;
+                let contact_distance = (paddle_collider.size.x + ball_collider.size.x) / half;
+                let contact_x = match side {
+                    Side::Left => paddle_position.x + contact_distance,
+                    Side::Right => paddle_position.x - contact_distance,
+                };
+                predict_intercept(**ball_position, ball.velocity, contact_x, reach)
+                    + self.aim_offset
+            }
+            AiSituation::BallAway => Fixed::ZERO,
+        };
+
+        // slow down on the last tick so the paddle stops right at the target
+        let ticks_away = ((target - paddle_position.y) / paddle.speed).to_f32();
+        input.axis = PaddleInput::analog_axis(ticks_away.clamp(-1., 1.) * handicap.tracking_speed);
+        input
     }
 }
 
 fn setup_local_player_controllers(
     mut commands: Commands,
     gametype: Res<CurrentState<LocalGameType>>,
-    mut paddle_set: ParamSet<(
-        Query<Entity, (Added<Paddle>, With<LeftPlayer>)>,
-        Query<Entity, (Added<Paddle>, With<RightPlayer>)>,
-    )>,
+    difficulty: Res<AiDifficulty>,
+    bindings: Res<KeyBindings>,
+    paddle_query: Query<(Entity, Option<&LeftPlayer>), Added<Paddle>>,
 ) {
-    if let Ok(left_paddle) = paddle_set.p0().get_single() {
-        commands.entity(left_paddle).insert(PlayerController);
+    let ai_sides = gametype.0.ai_sides();
+    for (paddle, left) in &paddle_query {
+        let side = if left.is_some() {
+            Side::Left
+        } else {
+            Side::Right
+        };
+        let controller = if ai_sides.contains(&side) {
+            PaddleController::Ai(AiController::new(*difficulty))
+        } else if ai_sides.is_empty() {
+            // each player gets the keys and the half of the screen on their side
+            let mut controls = PlayerControls::new(bindings.scheme(side));
+            controls.zone = Some(side);
+            PaddleController::Player(controls)
+        } else {
+            // a player alone on the keyboard can use either set of keys
+            PaddleController::Player(PlayerControls::new(bindings.either()))
+        };
+        commands.entity(paddle).insert(controller);
     }
+}
 
-    if let Ok(right_paddle) = paddle_set.p1().get_single() {
-        match gametype.0 {
-            LocalGameType::SinglePlayer => {
-                commands.entity(right_paddle).insert(AiController);
-            }
-            LocalGameType::MultiPlayer => {
-                commands.entity(right_paddle).insert(PlayerController);
+/// Hand out connected gamepads to the players, the left one first, and pause the match when a
+/// player's gamepad goes away
+fn assign_gamepads(
+    mut commands: Commands,
+    gamepads: Res<Gamepads>,
+    mut controller_query: Query<(&mut PaddleController, Option<&LeftPlayer>)>,
+) {
+    let mut players: Vec<_> = controller_query
+        .iter_mut()
+        .filter_map(|(controller, left)| {
+            let side = if left.is_some() {
+                Side::Left
+            } else {
+                Side::Right
+            };
+            match controller.into_inner() {
+                PaddleController::Player(controls) => Some((side, controls)),
+                _ => None,
             }
-            _ => (),
-        };
+        })
+        .collect();
+    players.sort_by_key(|(side, _)| *side != Side::Left);
+
+    for (_, controls) in &mut players {
+        if matches!(controls.gamepad, Some(gamepad) if !gamepads.contains(gamepad)) {
+            controls.gamepad = None;
+            commands.insert_resource(Paused("Gamepad disconnected"));
+        }
+    }
+
+    let mut free: Vec<Gamepad> = gamepads
+        .iter()
+        .filter(|gamepad| {
+            !players
+                .iter()
+                .any(|(_, controls)| controls.gamepad == Some(*gamepad))
+        })
+        .collect();
+    free.sort_by_key(|gamepad| gamepad.id);
+    let mut free = free.into_iter();
+    for (_, controls) in &mut players {
+        if controls.gamepad.is_none() {
+            controls.gamepad = free.next();
+        }
+    }
+}
+
+/// A local match on hold, with the reason shown to the players
+#[derive(Resource)]
+pub(crate) struct Paused(&'static str);
+
+/// Shown while a local match is paused
+#[derive(Component)]
+struct PauseText;
+
+fn show_paused(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    paused: Option<Res<Paused>>,
+    text_query: Query<Entity, With<PauseText>>,
+) {
+    let Some(paused) = paused else {
+        for text in &text_query {
+            commands.entity(text).despawn_recursive();
+        }
+        return;
+    };
+    if !text_query.is_empty() {
+        return;
+    }
+
+    commands
+        .spawn((
+            PauseText,
+            NodeBundle {
+                style: Style {
+                    size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                    position_type: PositionType::Absolute,
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn(
+                TextBundle::from_section(
+                    format!("{}\n\nEnter to continue\nEscape for the menu", paused.0),
+                    TextStyle {
+                        font: asset_server.load("fonts/PublicPixel-z84yD.ttf"),
+                        font_size: 40.,
+                        ..default()
+                    },
+                )
+                .with_text_alignment(TextAlignment::CENTER),
+            );
+        });
+}
+
+/// Pause the match, or resume it, with the pause key or the gamepad of one of the players
+fn toggle_pause(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    buttons: Res<Input<GamepadButton>>,
+    pong_state: Res<PongState>,
+    paused: Option<Res<Paused>>,
+    controller_query: Query<&PaddleController>,
+) {
+    if matches!(*pong_state, PongState::GameOver(_)) {
+        return;
+    }
+    let pressed = controller_query.iter().any(|controller| match controller {
+        PaddleController::Player(controls) => {
+            controls.pause_just_pressed(&keyboard_input, &buttons)
+        }
+        _ => false,
+    });
+    if !pressed {
+        return;
+    }
+    if paused.is_some() {
+        commands.remove_resource::<Paused>();
+    } else {
+        commands.insert_resource(Paused("Paused"));
+    }
+}
+
+/// Leave a paused match, or resume it with Enter. The Start button of the players resumes it in
+/// [`toggle_pause`].
+fn resume_local_match(mut commands: Commands, keyboard_input: Res<Input<KeyCode>>) {
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        commands.insert_resource(NextState(GameState::MainMenu));
+    } else if keyboard_input.just_pressed(KeyCode::Return) {
+        commands.remove_resource::<Paused>();
+    }
+}
+
+fn remove_paused(mut commands: Commands) {
+    commands.remove_resource::<Paused>();
+}
+
+/// Shown once a local match is over
+#[derive(Component)]
+struct GameOverText;
+
+fn show_game_over(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    pong_state: Res<PongState>,
+    local_game_type: Res<CurrentState<LocalGameType>>,
+    text_query: Query<Entity, With<GameOverText>>,
+) {
+    let PongState::GameOver(winner) = *pong_state else {
+        for text in &text_query {
+            commands.entity(text).despawn_recursive();
+        }
+        return;
+    };
+    if !text_query.is_empty() {
+        return;
     }
+
+    let outcome = match (&local_game_type.0, winner) {
+        (LocalGameType::SinglePlayer, Side::Left) => "You win!",
+        (LocalGameType::SinglePlayer, Side::Right) => "You lose!",
+        (LocalGameType::MultiPlayer | LocalGameType::Demo, Side::Left) => "Left player wins!",
+        (LocalGameType::MultiPlayer | LocalGameType::Demo, Side::Right) => "Right player wins!",
+    };
+    commands
+        .spawn((
+            GameOverText,
+            NodeBundle {
+                style: Style {
+                    size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                    position_type: PositionType::Absolute,
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn(
+                TextBundle::from_section(
+                    format!("{outcome}\n\nEnter to play again\nEscape for the menu"),
+                    TextStyle {
+                        font: asset_server.load("fonts/PublicPixel-z84yD.ttf"),
+                        font_size: 40.,
+                        ..default()
+                    },
+                )
+                .with_text_alignment(TextAlignment::CENTER),
+            );
+        });
+}
+
+fn restart_local_match(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    config: Res<PongConfig>,
+    pong_state: Res<PongState>,
+    mut paddle_query: Query<(&mut Paddle, &mut Position, Option<&LeftPlayer>), Without<Ball>>,
+    mut ball_query: Query<(&mut Ball, &mut Position), Without<Paddle>>,
+) {
+    if !matches!(*pong_state, PongState::GameOver(_)) {
+        return;
+    }
+
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        commands.insert_resource(NextState(GameState::MainMenu));
+    } else if keyboard_input.just_pressed(KeyCode::Return) {
+        reset_match_state(&mut commands);
+        reset_court(&config, &mut paddle_query, &mut ball_query);
+    }
+}
+
+/// Local matches are played in game, and the demo behind the main menu
+fn local_match_running(
+    game_state: Res<CurrentState<GameState>>,
+    game_type: Res<CurrentState<GameType>>,
+    local_game_type: Res<CurrentState<LocalGameType>>,
+) -> bool {
+    match game_state.0 {
+        GameState::Ingame => game_type.0 == GameType::Local,
+        GameState::MainMenu => local_game_type.0 == LocalGameType::Demo,
+        _ => false,
+    }
+}
+
+/// The ticks of a local match only run while it is not paused
+fn local_match_playing(
+    game_state: Res<CurrentState<GameState>>,
+    game_type: Res<CurrentState<GameType>>,
+    local_game_type: Res<CurrentState<LocalGameType>>,
+    paused: Option<Res<Paused>>,
+) -> bool {
+    paused.is_none() && local_match_running(game_state, game_type, local_game_type)
+}
+
+fn insert_timestep(mut commands: Commands) {
+    commands.insert_resource(Timestep(TIMESTEP));
 }
 
 pub struct LocalPlugin;
 
 impl Plugin for LocalPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(LocalConfig {
-            ai_handicap: AiHandicap {
-                view_percentage: 0.5,
-            },
-        })
-        .add_loopless_state(LocalGameType::SinglePlayer);
+        app.init_resource::<LocalConfig>()
+            .init_resource::<AiDifficulty>()
+            .init_resource::<PointerInput>()
+            .add_loopless_state(LocalGameType::SinglePlayer);
 
-        app.add_system(
-            setup_local_player_controllers
-                .run_in_state(GameType::Local)
-                .run_in_state(GameState::Ingame),
-        );
+        app.add_system(setup_local_player_controllers.run_if(local_match_running))
+            .add_system_set(
+                ConditionSet::new()
+                    .run_in_state(GameType::Local)
+                    .run_in_state(GameState::Ingame)
+                    .with_system(assign_gamepads)
+                    .with_system(toggle_pause)
+                    .with_system(show_game_over)
+                    .with_system(show_paused)
+                    .with_system(restart_local_match)
+                    .into(),
+            )
+            .add_system(
+                resume_local_match
+                    .run_in_state(GameType::Local)
+                    .run_in_state(GameState::Ingame)
+                    .run_if_resource_exists::<Paused>(),
+            )
+            // nothing serves while paused, so a click on the pause screen doesn't serve on resume
+            .add_system(
+                clear_pointer_taps
+                    .run_in_state(GameType::Local)
+                    .run_in_state(GameState::Ingame)
+                    .run_if_resource_exists::<Paused>(),
+            )
+            .add_enter_system(
+                GameState::Ingame,
+                insert_timestep.run_in_state(GameType::Local),
+            )
+            .add_exit_system(GameState::Ingame, despawn_with::<GameOverText>)
+            .add_exit_system(GameState::Ingame, despawn_with::<PauseText>)
+            .add_exit_system(GameState::Ingame, remove_paused)
+            .add_exit_system(GameState::Ingame, reset_pointer_input)
+            // online matches read the pointer too, for the player at this machine
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                pointer_input
+                    .run_in_state(GameState::Ingame)
+                    .after(InputSystem),
+            );
 
-        app.add_fixed_timestep(Duration::from_millis(30), "fixed_timestep")
+        app.add_fixed_timestep(TIMESTEP, "fixed_timestep")
             .add_fixed_timestep_system_set(
                 "fixed_timestep",
                 0,
                 ConditionSet::new()
-                    .run_in_state(GameType::Local)
-                    .run_in_state(GameState::Ingame)
+                    .run_if(local_match_playing)
                     .label(GameloopStage::Input)
-                    .with_system(keyboard_input)
-                    .with_system(ai_input.run_in_state(LocalGameType::SinglePlayer))
+                    .with_system(controller_input)
                     .into(),
             )
             .add_fixed_timestep_system_set(
                 "fixed_timestep",
                 0,
                 ConditionSet::new()
-                    .run_in_state(GameType::Local)
-                    .run_in_state(GameState::Ingame)
-                    .run_in_state(PongState::Serve(Side::Left))
+                    .run_if(local_match_playing)
+                    .run_if_resource_equals(PongState::Serve(Side::Left))
                     .after(GameloopStage::Input)
                     .with_system(serve::<LeftPlayer>)
                     .into(),
@@ -188,9 +620,8 @@ impl Plugin for LocalPlugin {
                 "fixed_timestep",
                 0,
                 ConditionSet::new()
-                    .run_in_state(GameType::Local)
-                    .run_in_state(GameState::Ingame)
-                    .run_in_state(PongState::Serve(Side::Right))
+                    .run_if(local_match_playing)
+                    .run_if_resource_equals(PongState::Serve(Side::Right))
                     .after(GameloopStage::Input)
                     .with_system(serve::<RightPlayer>)
                     .into(),
@@ -199,18 +630,7 @@ impl Plugin for LocalPlugin {
                 "fixed_timestep",
                 0,
                 paddle_movement
-                    .run_in_state(GameType::Local)
-                    .run_in_state(GameState::Ingame)
-                    .after(GameloopStage::Input)
-                    .label(GameloopStage::Movement),
-            )
-            .add_fixed_timestep_system(
-                "fixed_timestep",
-                0,
-                ball_movement
-                    .run_in_state(GameType::Local)
-                    .run_in_state(GameState::Ingame)
-                    .run_in_state(PongState::Playing)
+                    .run_if(local_match_playing)
                     .after(GameloopStage::Input)
                     .label(GameloopStage::Movement),
             )
@@ -218,9 +638,8 @@ impl Plugin for LocalPlugin {
                 "fixed_timestep",
                 0,
                 ball_collision
-                    .run_in_state(GameType::Local)
-                    .run_in_state(GameState::Ingame)
-                    .run_in_state(PongState::Playing)
+                    .run_if(local_match_playing)
+                    .run_if_resource_equals(PongState::Playing)
                     .after(GameloopStage::Movement)
                     .label(GameloopStage::Collision),
             )
@@ -228,19 +647,201 @@ impl Plugin for LocalPlugin {
                 "fixed_timestep",
                 0,
                 ball_scoring
-                    .run_in_state(GameType::Local)
-                    .run_in_state(GameState::Ingame)
-                    .run_in_state(PongState::Playing)
+                    .run_if(local_match_playing)
+                    .run_if_resource_equals(PongState::Playing)
                     .after(GameloopStage::Collision)
                     .label(GameloopStage::Scoring),
             )
+            .add_fixed_timestep_system(
+                "fixed_timestep",
+                0,
+                update_match_state
+                    .run_if(local_match_playing)
+                    .after(GameloopStage::Scoring)
+                    .label(GameloopStage::MatchState),
+            )
             .add_fixed_timestep_system(
                 "fixed_timestep",
                 0,
                 scoreboard
                     .run_in_state(GameType::Local)
                     .run_in_state(GameState::Ingame)
-                    .after(GameloopStage::Scoring),
+                    .after(GameloopStage::MatchState),
             );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pong::{tests::simulation_app, BallSpeed, PaddleInputs, RallyLength, Score};
+
+    fn fixed(value: f32) -> Fixed {
+        Fixed::from_f32(value)
+    }
+
+    fn vec(x: f32, y: f32) -> FixedVec2 {
+        FixedVec2::new(fixed(x), fixed(y))
+    }
+
+    #[test]
+    fn intercepts_are_predicted_through_wall_bounces() {
+        let reach = fixed(490.);
+        let predict =
+            |position, velocity| predict_intercept(position, velocity, fixed(600.), reach);
+
+        assert_eq!(predict(vec(0., 0.), vec(20., 0.)), Fixed::ZERO);
+        assert_eq!(predict(vec(0., 100.), vec(20., 5.)), fixed(250.));
+        // up 390 to the top wall, 210 back down
+        assert_eq!(predict(vec(0., 100.), vec(20., 20.)), fixed(280.));
+        // up 390 to the top wall, 810 back down
+        assert_eq!(predict(vec(0., 100.), vec(5., 10.)), fixed(-320.));
+        assert_eq!(predict(vec(0., -100.), vec(5., -10.)), fixed(320.));
+        // off both walls, 390 up, 980 down and 130 up again
+        assert_eq!(predict(vec(0., 100.), vec(4., 10.)), fixed(-360.));
+        // coming from the other side
+        assert_eq!(
+            predict_intercept(vec(0., 100.), vec(-20., 20.), fixed(-600.), reach),
+            fixed(280.)
+        );
+    }
+
+    #[test]
+    fn difficulties_wrap_around() {
+        assert_eq!(AiDifficulty::Normal.step(1), AiDifficulty::Hard);
+        assert_eq!(AiDifficulty::Easy.step(-1), AiDifficulty::Impossible);
+        assert_eq!(AiDifficulty::Impossible.step(1), AiDifficulty::Easy);
+    }
+
+    /// Headless match with the impossible AI playing `ai_sides`, and the ball in play from the
+    /// center of the court at `velocity`
+    fn ai_app(ai_sides: &[Side], velocity: FixedVec2) -> App {
+        let mut app = simulation_app();
+        app.init_resource::<LocalConfig>()
+            .init_resource::<Input<KeyCode>>()
+            .init_resource::<Input<GamepadButton>>()
+            .init_resource::<Axis<GamepadAxis>>()
+            .init_resource::<PointerInput>()
+            .add_system(controller_input.before(GameloopStage::Input));
+        app.update();
+
+        let mut paddles = app.world.query::<(Entity, Option<&LeftPlayer>)>();
+        let ai_paddles: Vec<Entity> = paddles
+            .iter(&app.world)
+            .filter(|(_, left)| {
+                ai_sides.contains(&if left.is_some() {
+                    Side::Left
+                } else {
+                    Side::Right
+                })
+            })
+            .map(|(paddle, _)| paddle)
+            .collect();
+        for paddle in ai_paddles {
+            app.world
+                .entity_mut(paddle)
+                .insert(PaddleController::Ai(AiController::new(
+                    AiDifficulty::Impossible,
+                )));
+        }
+
+        app.insert_resource(PongState::Playing)
+            .insert_resource(BallSpeed(velocity.length()));
+        let mut balls = app.world.query::<(&mut Ball, &mut Position)>();
+        let (mut ball, mut position) = balls.single_mut(&mut app.world);
+        ball.velocity = velocity;
+        **position = FixedVec2::ZERO;
+        app
+    }
+
+    fn paddle_heights(app: &mut App) -> (Fixed, Fixed) {
+        let mut paddles = app
+            .world
+            .query_filtered::<(&Position, Option<&LeftPlayer>), With<Paddle>>();
+        let mut heights = (Fixed::ZERO, Fixed::ZERO);
+        for (position, left) in paddles.iter(&app.world) {
+            match left {
+                Some(_) => heights.0 = position.y,
+                None => heights.1 = position.y,
+            }
+        }
+        heights
+    }
+
+    #[test]
+    fn ai_paddles_track_the_ball_independently() {
+        // the ball meets the right paddle after 31 ticks, 155.4 up, where the AI stops its paddle
+        let mut app = ai_app(&[Side::Left, Side::Right], vec(20., 5.));
+        app.update();
+        let inputs = app.world.resource::<PaddleInputs>();
+        assert_eq!(inputs[0].axis, 0);
+        assert!(inputs[1].axis > 0);
+
+        for _ in 0..25 {
+            app.update();
+        }
+        let (left, right) = paddle_heights(&mut app);
+        assert_eq!(left, Fixed::ZERO);
+        assert!((right - fixed(155.4)).abs() <= fixed(1.), "{right:?}");
+
+        // the same for the left paddle, with only it played by the AI
+        let mut app = ai_app(&[Side::Left], vec(-20., -5.));
+        for _ in 0..26 {
+            app.update();
+        }
+        let (left, right) = paddle_heights(&mut app);
+        assert!((left - fixed(-155.4)).abs() <= fixed(1.), "{left:?}");
+        assert_eq!(right, Fixed::ZERO);
+    }
+
+    #[test]
+    fn impossible_ais_keep_the_rally_going() {
+        let mut app = ai_app(&[Side::Left, Side::Right], vec(20., 8.));
+        for _ in 0..500 {
+            app.update();
+        }
+        let score = app.world.resource::<Score>();
+        assert_eq!((score.left, score.right), (0, 0));
+        assert!(app.world.resource::<RallyLength>().0 >= 5);
+    }
+
+    #[test]
+    fn gamepads_go_to_the_left_player_first_and_pause_when_lost() {
+        use bevy::input::gamepad::{
+            gamepad_connection_system, GamepadEvent, GamepadEventType, GamepadInfo,
+        };
+
+        let mut app = App::new();
+        app.add_event::<GamepadEvent>()
+            .init_resource::<Gamepads>()
+            .add_system(gamepad_connection_system)
+            .add_system(assign_gamepads.after(gamepad_connection_system));
+        let controls =
+            || PaddleController::Player(PlayerControls::new(KeyBindings::default().either()));
+        let right = app.world.spawn(controls()).id();
+        let left = app.world.spawn((controls(), LeftPlayer)).id();
+
+        let connect = |app: &mut App, id, event_type| {
+            app.world
+                .send_event(GamepadEvent::new(Gamepad::new(id), event_type));
+            app.update();
+        };
+        let info = || GamepadInfo {
+            name: "pad".to_string(),
+        };
+        connect(&mut app, 3, GamepadEventType::Connected(info()));
+        connect(&mut app, 1, GamepadEventType::Connected(info()));
+        let gamepad = |app: &App, paddle| match app.world.get::<PaddleController>(paddle) {
+            Some(PaddleController::Player(controls)) => controls.gamepad,
+            _ => unreachable!(),
+        };
+        assert_eq!(gamepad(&app, left), Some(Gamepad::new(3)));
+        assert_eq!(gamepad(&app, right), Some(Gamepad::new(1)));
+        assert!(app.world.get_resource::<Paused>().is_none());
+
+        connect(&mut app, 3, GamepadEventType::Disconnected);
+        assert_eq!(gamepad(&app, left), None);
+        assert_eq!(gamepad(&app, right), Some(Gamepad::new(1)));
+        assert!(app.world.get_resource::<Paused>().is_some());
+    }
+}
@@ -2,44 +2,82 @@ use bevy::prelude::*;
 
 use iyes_loopless::prelude::*;
 
+use args::Args;
+use attract::AttractPlugin;
+use config::ConfigPlugin;
+use controls::ControlsPlugin;
 use local::LocalPlugin;
 use menu::MenuPlugin;
 #[cfg(feature = "online")]
 use online::OnlinePlugin;
 use pong::{setup_camera, PongPlugin};
+#[cfg(feature = "online")]
+use settings::SettingsPlugin;
+#[cfg(feature = "online")]
+use synctest::SyncTestPlugin;
 
-use types::GameType;
-
-use crate::types::GameState;
-
+mod args;
+mod attract;
+mod collision;
+mod config;
+mod controller;
+mod controls;
+mod fixed;
 mod local;
 mod menu;
 #[cfg(feature = "online")]
 mod online;
 mod pong;
+#[cfg(feature = "online")]
+mod settings;
+#[cfg(feature = "online")]
+mod socket;
+#[cfg(feature = "online")]
+mod synctest;
 mod types;
 mod util;
 
 fn main() {
+    let args = Args::from_env();
+
+    let (initial_state, initial_type) = args.initial_state();
+
     let mut app = App::new();
 
-    app.add_plugins(DefaultPlugins.set(WindowPlugin {
-        window: WindowDescriptor {
-            fit_canvas_to_parent: true,
-            ..default()
-        },
-        ..default()
-    }))
+    app.add_plugins(
+        DefaultPlugins
+            .set(WindowPlugin {
+                window: WindowDescriptor {
+                    fit_canvas_to_parent: true,
+                    ..default()
+                },
+                ..default()
+            })
+            .set(AssetPlugin {
+                // hot reload the config, the web has no files to watch
+                watch_for_changes: cfg!(not(target_arch = "wasm32")),
+                ..default()
+            }),
+    )
     .insert_resource(ClearColor(Color::BLACK))
-    .add_loopless_state(GameState::MainMenu)
-    .add_loopless_state(GameType::Local)
+    .insert_resource(args.clone())
+    .add_loopless_state(initial_state)
+    .add_loopless_state(initial_type)
     .add_startup_system(setup_camera)
     .add_plugin(MenuPlugin)
     .add_plugin(PongPlugin)
-    .add_plugin(LocalPlugin);
+    .add_plugin(LocalPlugin)
+    .add_plugin(AttractPlugin)
+    .add_plugin(ConfigPlugin)
+    .add_plugin(ControlsPlugin);
 
     #[cfg(feature = "online")]
-    app.add_plugin(OnlinePlugin);
+    {
+        app.add_plugin(OnlinePlugin).add_plugin(SettingsPlugin);
+        if args.synctest {
+            app.add_plugin(SyncTestPlugin);
+        }
+    }
 
     app.run();
 }
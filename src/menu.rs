@@ -2,14 +2,35 @@ use bevy::prelude::*;
 
 use iyes_loopless::prelude::*;
 
-use crate::local::LocalGameType;
+use crate::attract::AttractInput;
+use crate::controls::ControlsButton;
+use crate::local::{AiDifficulty, LocalGameType};
+#[cfg(feature = "online")]
+use crate::settings::SettingsButton;
 
 use crate::types::{GameType, MainMenu};
 use crate::util::despawn_with;
 
 use crate::types::GameState;
 
-fn setup_menu(mut commands: Commands, asset_server: Res<AssetServer>) {
+/// Steps the AI difficulty back or forward when clicked
+#[derive(Component)]
+struct DifficultyButton {
+    step: i32,
+}
+
+#[derive(Component)]
+struct DifficultyText;
+
+fn difficulty_label(difficulty: AiDifficulty) -> String {
+    format!("AI: {difficulty}")
+}
+
+fn setup_menu(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    difficulty: Res<AiDifficulty>,
+) {
     let font = asset_server.load("fonts/PublicPixel-z84yD.ttf");
     let text_style = TextStyle {
         font,
@@ -53,6 +74,45 @@ fn setup_menu(mut commands: Commands, asset_server: Res<AssetServer>) {
                     parent_button.spawn(TextBundle::from_section("1 Player", text_style.clone()));
                 });
 
+            let small_text_style = TextStyle {
+                font_size: 20.,
+                ..text_style.clone()
+            };
+            let step_button_bundle = ButtonBundle {
+                style: Style {
+                    margin: UiRect::horizontal(Val::Px(20.)),
+                    ..button_bundle.style.clone()
+                },
+                ..button_bundle.clone()
+            };
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    ..default()
+                })
+                .with_children(|parent| {
+                    parent
+                        .spawn((DifficultyButton { step: -1 }, step_button_bundle.clone()))
+                        .with_children(|parent| {
+                            parent.spawn(TextBundle::from_section("<", small_text_style.clone()));
+                        });
+                    parent.spawn((
+                        DifficultyText,
+                        TextBundle::from_section(
+                            difficulty_label(*difficulty),
+                            small_text_style.clone(),
+                        ),
+                    ));
+                    parent
+                        .spawn((DifficultyButton { step: 1 }, step_button_bundle))
+                        .with_children(|parent| {
+                            parent.spawn(TextBundle::from_section(">", small_text_style));
+                        });
+                });
+
             parent
                 .spawn((
                     GameType::Local,
@@ -65,13 +125,27 @@ fn setup_menu(mut commands: Commands, asset_server: Res<AssetServer>) {
 
             #[cfg(feature = "online")]
             parent
-                .spawn((GameType::Online, button_bundle))
+                .spawn((GameType::Online, button_bundle.clone()))
                 .with_children(|parent_button| {
                     parent_button.spawn(TextBundle::from_section("Online", text_style.clone()));
                 });
+
+            parent
+                .spawn((ControlsButton, button_bundle.clone()))
+                .with_children(|parent_button| {
+                    parent_button.spawn(TextBundle::from_section("Controls", text_style.clone()));
+                });
+
+            #[cfg(feature = "online")]
+            parent
+                .spawn((SettingsButton, button_bundle))
+                .with_children(|parent_button| {
+                    parent_button.spawn(TextBundle::from_section("Settings", text_style.clone()));
+                });
         });
 }
 
+#[allow(clippy::type_complexity)]
 fn gametype_button(
     mut commands: Commands,
     interaction_query: Query<
@@ -100,12 +174,46 @@ fn gametype_button(
     }
 }
 
+fn difficulty_buttons(
+    mut difficulty: ResMut<AiDifficulty>,
+    interaction_query: Query<(&Interaction, &DifficultyButton), Changed<Interaction>>,
+) {
+    for (interaction, button) in &interaction_query {
+        if *interaction == Interaction::Clicked {
+            *difficulty = difficulty.step(button.step);
+        }
+    }
+}
+
+fn difficulty_text(
+    difficulty: Res<AiDifficulty>,
+    mut text_query: Query<&mut Text, With<DifficultyText>>,
+) {
+    if !difficulty.is_changed() {
+        return;
+    }
+    for mut text in &mut text_query {
+        text.sections[0].value = difficulty_label(*difficulty);
+    }
+}
+
 pub(crate) struct MenuPlugin;
 
 impl Plugin for MenuPlugin {
     fn build(&self, app: &mut App) {
         app.add_enter_system(GameState::MainMenu, setup_menu)
             .add_exit_system(GameState::MainMenu, despawn_with::<MainMenu>)
-            .add_system(gametype_button.run_in_state(GameState::MainMenu));
+            .add_system(
+                gametype_button
+                    .run_in_state(GameState::MainMenu)
+                    .after(AttractInput),
+            )
+            .add_system_set(
+                ConditionSet::new()
+                    .run_in_state(GameState::MainMenu)
+                    .with_system(difficulty_buttons)
+                    .with_system(difficulty_text)
+                    .into(),
+            );
     }
 }
@@ -1,17 +1,39 @@
-use bevy::{prelude::*, tasks::IoTaskPool};
+use std::{
+    any::Any,
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bevy::{
+    prelude::*,
+    tasks::IoTaskPool,
+    utils::{HashMap, HashSet},
+};
 use bevy_ggrs::{GGRSPlugin, PlayerInputs, Rollback, RollbackIdProvider, Session};
 use bytemuck::{Pod, Zeroable};
-use ggrs::{Config, InputStatus, PlayerHandle, SessionBuilder};
+use futures_lite::FutureExt;
+use ggrs::{Config, GGRSEvent, P2PSession, PlayerHandle, PlayerType, SessionBuilder, SessionState};
 use iyes_loopless::prelude::*;
 use matchbox_socket::WebRtcSocket;
+use rand::seq::SliceRandom;
 
 use crate::{
+    args::{Args, InputDelay, MAX_INPUT_DELAY},
+    controller::{controller_input, ControllerSources, PaddleController, PlayerControls},
+    controls::KeyBindings,
+    fixed::FixedVec2,
     pong::{
-        ball_collision, ball_movement, ball_scoring, paddle_movement, scoreboard, serve, Ball,
-        GameloopStage, LeftPlayer, Paddle, PaddleInput, PaddleInputs, PongState, RightPlayer, Side,
+        ball_collision, ball_scoring, paddle_movement, reset_court, reset_match_state, scoreboard,
+        serve, update_match_state, Ball, BallSpeed, Collider, GameloopStage, LeftPlayer,
+        MatchClock, Paddle, PaddleInput, PongConfig, PongState, Position, RallyLength, RightPlayer,
+        Score, Side, Timestep,
     },
-    types::GameType,
-    GameState,
+    socket::{GameMessage, Hello, MatchSnapshot, MatchSocket, Role},
+    synctest::{check_rollback_state, ChecksumHistory},
+    types::{GameState, GameType},
+    util::despawn_with,
 };
 
 #[derive(Debug)]
@@ -26,19 +48,199 @@ impl Config for GGRSConfig {
 #[derive(Copy, Clone, PartialEq, Eq, Pod, Zeroable)]
 pub struct BoxInput {
     pub inp: u8,
+    /// `PaddleInput::axis`, already quantized so every peer moves the paddle by the same amount
+    pub axis: i8,
+}
+
+/// Players in a match, the court only has two paddles. Spectators don't count.
+const NUM_PLAYERS: usize = 2;
+
+/// Build the matchbox room url for a signalling server and room. Paired rooms hand every two peers
+/// a room of their own, the others put everyone that joins in the same room.
+fn room_url(server: &str, room: &str, paired: bool) -> Result<String, String> {
+    let server = server.trim_end_matches('/');
+    let host = server
+        .strip_prefix("wss://")
+        .or_else(|| server.strip_prefix("ws://"))
+        .ok_or_else(|| format!("{server} is not a ws:// or wss:// url"))?;
+    if host.is_empty() || host.starts_with('/') || host.contains(char::is_whitespace) {
+        return Err(format!("{server} is not a valid server url"));
+    }
+    if room.is_empty()
+        || !room
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "room name \"{room}\" may only contain letters, digits, - and _"
+        ));
+    }
+    if paired {
+        Ok(format!("{server}/{room}?next={NUM_PLAYERS}"))
+    } else {
+        Ok(format!("{server}/{room}"))
+    }
+}
+
+/// Characters of private room codes, leaving out the ones that are easy to mix up (0/O, 1/I)
+const ROOM_CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const ROOM_CODE_LEN: usize = 5;
+
+fn generate_room_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..ROOM_CODE_LEN)
+        .map(|_| *ROOM_CODE_CHARS.choose(&mut rng).unwrap() as char)
+        .collect()
+}
+
+/// Normalize a room code typed by a player, `None` if it can't be a code we generated
+fn parse_room_code(input: &str) -> Option<String> {
+    let code: String = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    (code.len() == ROOM_CODE_LEN && code.bytes().all(|c| ROOM_CODE_CHARS.contains(&c)))
+        .then_some(code)
+}
+
+/// Matchbox room id of a private room, named apart from the public room so nobody ends up in it
+/// by accident
+fn private_room_id(room: &str, code: &str) -> String {
+    format!("{room}-private-{}", code.to_ascii_lowercase())
+}
+
+/// Room picked on the lobby screen
+#[derive(Resource, Debug, Clone, PartialEq, Eq)]
+enum LobbyRoom {
+    /// Matched with whoever else is waiting in the configured room
+    Public,
+    /// Only matched with players that enter the same code
+    Private(String),
+    /// Watching the match of the players in a private room
+    Spectate(String),
+}
+
+impl LobbyRoom {
+    fn role(&self) -> Role {
+        match self {
+            LobbyRoom::Public | LobbyRoom::Private(_) => Role::Player,
+            LobbyRoom::Spectate(_) => Role::Spectator,
+        }
+    }
+}
+
+/// How often every peer announces its role to the others
+const ANNOUNCE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Everyone in the room and what they are there for, as announced by each of them
+#[derive(Resource)]
+struct PeerRoles {
+    local: Role,
+    remote: HashMap<String, Role>,
+    announce_timer: Timer,
+}
+
+impl PeerRoles {
+    fn new(local: Role) -> Self {
+        PeerRoles {
+            local,
+            remote: HashMap::default(),
+            announce_timer: Timer::new(ANNOUNCE_INTERVAL, TimerMode::Repeating),
+        }
+    }
+
+    /// How we announce ourselves to the other peers
+    fn hello(&self, args: &Args, config: &PongConfig) -> Hello {
+        Hello {
+            role: self.local,
+            fps: args.fps as u32,
+            config: config_hash(config),
+        }
+    }
+
+    /// Peer ids of the other peers still in the room with a role, sorted
+    fn peers(&self, role: Role, connected: &[String]) -> Vec<String> {
+        let mut peers: Vec<String> = self
+            .remote
+            .iter()
+            .filter(|(peer, &peer_role)| peer_role == role && connected.contains(peer))
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        peers.sort();
+        peers
+    }
+}
+
+/// Peer ids of the players in the match, in handle order. They are sorted so every peer agrees on
+/// the handles, and anyone beyond the number of players doesn't get to play.
+fn match_lineup(mut players: Vec<String>, num_players: usize) -> Vec<String> {
+    players.sort();
+    players.truncate(num_players);
+    players
+}
+
+/// Why the socket's message loop stopped, set from the task running it
+#[derive(Resource, Clone, Default)]
+struct SocketFailure(Arc<Mutex<Option<String>>>);
+
+impl SocketFailure {
+    fn take(&self) -> Option<String> {
+        self.0.lock().unwrap().take()
+    }
 }
 
-fn start_matchbox_socket(mut commands: Commands) {
-    let room_url = "wss://pong-signalling-server.fly.dev/pong?next=2";
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    panic
+        .downcast_ref::<String>()
+        .cloned()
+        .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+        .unwrap_or_else(|| "unknown error".to_string())
+}
+
+/// Drive the socket's message loop, which only stops when the signalling server can't be
+/// reached or drops the connection, and record why it stopped.
+async fn watch_message_loop(message_loop: impl Future<Output = ()>, failure: SocketFailure) {
+    // matchbox panics when it fails to connect, catch that instead of taking down the task pool
+    let reason = match AssertUnwindSafe(message_loop).catch_unwind().await {
+        Ok(()) => "the connection was closed".to_string(),
+        Err(panic) => panic_message(panic),
+    };
+    *failure.0.lock().unwrap() = Some(reason);
+}
+
+fn start_matchbox_socket(mut commands: Commands, args: Res<Args>, room: Res<LobbyRoom>) {
+    // private rooms are shared by the players and whoever comes to watch them
+    let (room_id, paired) = match room.as_ref() {
+        LobbyRoom::Public => (args.room.clone(), true),
+        LobbyRoom::Private(code) | LobbyRoom::Spectate(code) => {
+            (private_room_id(&args.room, code), false)
+        }
+    };
+    let room_url = match room_url(&args.server, &room_id, paired) {
+        Ok(room_url) => room_url,
+        Err(err) => {
+            error!("invalid signalling server settings: {err}");
+            commands.insert_resource(LobbyError(format!(
+                "Could not connect to the signalling server:\n{err}"
+            )));
+            return;
+        }
+    };
     info!("connecting to matchbox server: {:?}", room_url);
     let (socket, message_loop) = WebRtcSocket::new(room_url);
 
     // The message loop needs to be awaited, or nothing will happen.
     // We do this here using bevy's task system.
+    let failure = SocketFailure::default();
     let task_pool = IoTaskPool::get();
-    task_pool.spawn(message_loop).detach();
+    task_pool
+        .spawn(watch_message_loop(message_loop, failure.clone()))
+        .detach();
 
-    commands.insert_resource(Socket(Some(socket)));
+    commands.insert_resource(MatchSocket::new(socket));
+    commands.insert_resource(PeerRoles::new(room.role()));
+    commands.insert_resource(failure);
 }
 
 #[derive(Component)]
@@ -46,34 +248,145 @@ struct LobbyText;
 #[derive(Component)]
 struct LobbyUI;
 
-fn setup_lobby(mut commands: Commands, asset_server: Res<AssetServer>) {
+/// Parent of the buttons to pick a room, removed once a room is picked
+#[derive(Component)]
+struct LobbyChoices;
+
+#[derive(Component, Debug, Clone, Copy)]
+enum LobbyButton {
+    QuickMatch,
+    CreateRoom,
+    JoinRoom,
+    WatchRoom,
+}
+
+#[derive(Component)]
+struct RoomCodeText;
+
+/// Room code typed into the lobby so far
+#[derive(Resource, Default)]
+struct RoomCodeInput(String);
+
+/// Shown in the lobby instead of the player count when we can't join a match
+#[derive(Resource)]
+struct LobbyError(String);
+
+fn setup_lobby(mut commands: Commands, asset_server: Res<AssetServer>, args: Res<Args>) {
+    let font = asset_server.load("fonts/PublicPixel-z84yD.ttf");
+    let text_style = TextStyle {
+        font: font.clone(),
+        font_size: 32.,
+        ..default()
+    };
+    let button_bundle = ButtonBundle {
+        style: Style {
+            margin: UiRect::all(Val::Px(20.)),
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            ..default()
+        },
+        background_color: Color::BLACK.into(),
+        ..default()
+    };
+
+    commands.init_resource::<RoomCodeInput>();
     commands
         .spawn(NodeBundle {
             style: Style {
                 size: Size::new(Val::Percent(100.), Val::Percent(100.)),
                 position_type: PositionType::Absolute,
+                flex_direction: FlexDirection::ColumnReverse,
                 justify_content: JustifyContent::Center,
-                align_items: AlignItems::FlexEnd,
+                align_items: AlignItems::Center,
                 ..default()
             },
             ..default()
         })
         .with_children(|parent| {
+            parent
+                .spawn((
+                    LobbyChoices,
+                    NodeBundle {
+                        style: Style {
+                            flex_direction: FlexDirection::ColumnReverse,
+                            align_items: AlignItems::Center,
+                            ..default()
+                        },
+                        ..default()
+                    },
+                ))
+                .with_children(|parent| {
+                    for (button, label) in [
+                        (LobbyButton::QuickMatch, "Quick match"),
+                        (LobbyButton::CreateRoom, "Create room"),
+                    ] {
+                        parent
+                            .spawn((button, button_bundle.clone()))
+                            .with_children(|parent| {
+                                parent.spawn(TextBundle::from_section(label, text_style.clone()));
+                            });
+                    }
+
+                    parent
+                        .spawn(NodeBundle {
+                            style: Style {
+                                align_items: AlignItems::Center,
+                                ..default()
+                            },
+                            ..default()
+                        })
+                        .with_children(|parent| {
+                            parent.spawn((
+                                RoomCodeText,
+                                TextBundle::from_section(
+                                    "_".repeat(ROOM_CODE_LEN),
+                                    text_style.clone(),
+                                ),
+                            ));
+                            for (button, label) in [
+                                (LobbyButton::JoinRoom, "Join"),
+                                (LobbyButton::WatchRoom, "Watch"),
+                            ] {
+                                parent.spawn((button, button_bundle.clone())).with_children(
+                                    |parent| {
+                                        parent.spawn(TextBundle::from_section(
+                                            label,
+                                            text_style.clone(),
+                                        ));
+                                    },
+                                );
+                            }
+                        });
+                });
+
             parent
                 .spawn(TextBundle {
                     style: Style {
-                        align_self: AlignSelf::Center,
-                        justify_content: JustifyContent::Center,
+                        margin: UiRect::all(Val::Px(20.)),
                         ..default()
                     },
-                    text: Text::from_section(
-                        "Entering lobby...",
-                        TextStyle {
-                            font: asset_server.load("fonts/PublicPixel-z84yD.ttf"),
-                            font_size: 40.,
-                            color: Color::BLACK,
-                        },
-                    ),
+                    text: Text::from_sections([
+                        TextSection::new(
+                            "",
+                            TextStyle {
+                                font: font.clone(),
+                                font_size: 40.,
+                                ..default()
+                            },
+                        ),
+                        TextSection::new(
+                            format!(
+                                "\n\n{}\nroom: {}\n\nEscape to go back",
+                                args.server, args.room
+                            ),
+                            TextStyle {
+                                font,
+                                font_size: 16.,
+                                ..default()
+                            },
+                        ),
+                    ])
+                    .with_alignment(TextAlignment::CENTER),
                     ..default()
                 })
                 .insert(LobbyText);
@@ -81,48 +394,477 @@ fn setup_lobby(mut commands: Commands, asset_server: Res<AssetServer>) {
         .insert(LobbyUI);
 }
 
+fn type_room_code(
+    mut input: ResMut<RoomCodeInput>,
+    mut characters: EventReader<ReceivedCharacter>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut text_query: Query<&mut Text, With<RoomCodeText>>,
+) {
+    for character in characters.iter() {
+        let c = character.char.to_ascii_uppercase();
+        if input.0.len() < ROOM_CODE_LEN && c.is_ascii() && ROOM_CODE_CHARS.contains(&(c as u8)) {
+            input.0.push(c);
+        }
+    }
+    if keyboard_input.just_pressed(KeyCode::Back) {
+        input.0.pop();
+    }
+
+    if input.is_changed() {
+        text_query.single_mut().sections[0].value =
+            format!("{:_<width$}", input.0, width = ROOM_CODE_LEN);
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn pick_lobby_room(
+    mut commands: Commands,
+    interaction_query: Query<(&Interaction, &LobbyButton), (Changed<Interaction>, With<Button>)>,
+    keyboard_input: Res<Input<KeyCode>>,
+    code_input: Res<RoomCodeInput>,
+    choices_query: Query<Entity, With<LobbyChoices>>,
+    mut text_query: Query<&mut Text, With<LobbyText>>,
+) {
+    let mut picked = interaction_query
+        .iter()
+        .find(|(interaction, _)| **interaction == Interaction::Clicked)
+        .map(|(_, button)| *button);
+    if keyboard_input.just_pressed(KeyCode::Return) {
+        picked = Some(LobbyButton::JoinRoom);
+    }
+
+    let room = match picked {
+        None => return,
+        Some(LobbyButton::QuickMatch) => LobbyRoom::Public,
+        Some(LobbyButton::CreateRoom) => LobbyRoom::Private(generate_room_code()),
+        Some(button @ (LobbyButton::JoinRoom | LobbyButton::WatchRoom)) => {
+            match (parse_room_code(&code_input.0), button) {
+                (Some(code), LobbyButton::WatchRoom) => LobbyRoom::Spectate(code),
+                (Some(code), _) => LobbyRoom::Private(code),
+                (None, _) => {
+                    text_query.single_mut().sections[0].value =
+                        format!("Room codes are {ROOM_CODE_LEN} characters long");
+                    return;
+                }
+            }
+        }
+    };
+    info!("Lobby room picked: {:?}", room);
+
+    for choices in &choices_query {
+        commands.entity(choices).despawn_recursive();
+    }
+    text_query.single_mut().sections[0].value = "Entering lobby...".to_string();
+    commands.insert_resource(room);
+}
+
 fn lobby_cleanup(query: Query<Entity, With<LobbyUI>>, mut commands: Commands) {
     for e in query.iter() {
         commands.entity(e).despawn_recursive();
     }
+    commands.remove_resource::<LobbyRoom>();
+    commands.remove_resource::<RoomCodeInput>();
+    commands.remove_resource::<LatencyProbe>();
+    commands.remove_resource::<MatchLineup>();
+    commands.remove_resource::<LobbyError>();
+}
+
+/// The socket outlives the lobby so the game can keep talking to its peers, it is closed once
+/// we're back at the main menu
+fn close_socket(mut commands: Commands) {
+    commands.remove_resource::<MatchSocket>();
+    commands.remove_resource::<PeerRoles>();
+    commands.remove_resource::<SocketFailure>();
+}
+
+fn leave_lobby(mut commands: Commands, keyboard_input: Res<Input<KeyCode>>) {
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        commands.insert_resource(NextState(GameState::MainMenu));
+    }
+}
+
+fn show_lobby_error(error: Res<LobbyError>, mut text_query: Query<&mut Text, With<LobbyText>>) {
+    if error.is_added() {
+        text_query.single_mut().sections[0].value = error.0.clone();
+    }
+}
+
+/// Time between pings while measuring the round trip time to the other players
+const PING_INTERVAL: Duration = Duration::from_millis(100);
+/// Round trips measured to every player before the input delay is picked
+const PING_SAMPLES: usize = 5;
+/// Go with whatever was measured after this long
+const LATENCY_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Round trip times to the other players in the lobby, measured to pick the input delay
+#[derive(Resource)]
+struct LatencyProbe {
+    /// The other players, the spectators don't affect the input delay
+    players: Vec<String>,
+    round_trips: HashMap<String, Vec<Duration>>,
+    ping_timer: Timer,
+    timeout: Timer,
+}
+
+impl LatencyProbe {
+    fn new(players: Vec<String>) -> Self {
+        LatencyProbe {
+            players,
+            round_trips: HashMap::default(),
+            ping_timer: Timer::new(PING_INTERVAL, TimerMode::Repeating),
+            timeout: Timer::new(LATENCY_PROBE_TIMEOUT, TimerMode::Once),
+        }
+    }
+}
+
+/// Clock sent along with pings, wrapping around after about 50 days
+fn ping_clock(time: &Time) -> u32 {
+    time.raw_elapsed().as_millis() as u32
+}
+
+/// Input delay that covers the one way trip to the slowest player, going by the median of the
+/// round trips measured to each of them
+fn auto_input_delay<'a>(
+    round_trips: impl IntoIterator<Item = &'a Vec<Duration>>,
+    fps: usize,
+) -> usize {
+    round_trips
+        .into_iter()
+        .filter_map(|round_trips| {
+            let mut round_trips = round_trips.clone();
+            round_trips.sort();
+            round_trips.get(round_trips.len() / 2).copied()
+        })
+        .map(|round_trip| (round_trip.as_micros() as usize * fps + 1_999_999) / 2_000_000)
+        .max()
+        // nothing came back at all, assume the worst
+        .unwrap_or(MAX_INPUT_DELAY)
+        .min(MAX_INPUT_DELAY)
+}
+
+/// Announce our role to every peer in the room, right away to the ones that just connected
+fn announce_role(
+    time: Res<Time>,
+    socket: Res<MatchSocket>,
+    (args, config): (Res<Args>, Res<PongConfig>),
+    mut roles: ResMut<PeerRoles>,
+) {
+    let hello = GameMessage::Hello(roles.hello(&args, &config));
+    for peer in socket.accept_new_connections() {
+        socket.send(hello, &peer);
+    }
+    if roles.announce_timer.tick(time.delta()).just_finished() {
+        for peer in socket.connected_peers() {
+            socket.send(hello, &peer);
+        }
+    }
+}
+
+/// Hash of a game config that is the same on every platform, FNV-1a over its encoding
+fn config_hash(config: &PongConfig) -> u64 {
+    bincode::serialize(config)
+        .expect("the config can always be encoded")
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Why a peer that announced itself with `hello` can't be in a match with us, if it can't
+fn incompatible_peer(hello: &Hello, ours: &Hello) -> Option<String> {
+    if hello.fps != ours.fps {
+        return Some(format!(
+            "Another player runs at {} fps, this game at {}\nEveryone needs the same --fps",
+            hello.fps, ours.fps
+        ));
+    }
+    if hello.config != ours.config {
+        return Some(
+            "Another player has a different pong.config.ron\nEveryone needs the same config"
+                .to_string(),
+        );
+    }
+    None
+}
+
+/// Whether a session includes a peer, as a player or as a spectator
+fn in_session(session: &P2PSession<GGRSConfig>, peer: &str) -> bool {
+    !session.handles_by_address(peer.to_string()).is_empty()
 }
 
-#[derive(Resource, Deref, DerefMut)]
-struct Socket(Option<WebRtcSocket>);
+/// Handle the messages from our peers next to the GGRS traffic: answer and time pings, keep
+/// track of everyone's role, tell players who turn up after the match started that it did, and
+/// stream the match to spectators who do
+#[allow(clippy::type_complexity)]
+fn handle_game_messages(
+    mut commands: Commands,
+    time: Res<Time>,
+    socket: Res<MatchSocket>,
+    mut probe: Option<ResMut<LatencyProbe>>,
+    mut roles: Option<ResMut<PeerRoles>>,
+    session: Option<Res<Session<GGRSConfig>>>,
+    (mut late_spectators, mut relayed, game_state, args, config): (
+        ResMut<LateSpectators>,
+        Option<ResMut<RelayedMatch>>,
+        Res<CurrentState<GameState>>,
+        Res<Args>,
+        Res<PongConfig>,
+    ),
+) {
+    for (peer, message) in socket.receive() {
+        match message {
+            GameMessage::Ping(clock) => socket.send(GameMessage::Pong(clock), &peer),
+            GameMessage::Hello(hello) => {
+                let role = hello.role;
+                if let Some(roles) = roles.as_mut() {
+                    // a peer we can't play with never gets a role, so the lobby doesn't start
+                    if game_state.0 == GameState::Lobby {
+                        if let Some(err) = incompatible_peer(&hello, &roles.hello(&args, &config)) {
+                            warn!("{peer} can't join the match: {err}");
+                            commands.insert_resource(LobbyError(err));
+                            continue;
+                        }
+                    }
+                    if roles.remote.insert(peer.clone(), role) != Some(role) {
+                        info!("{peer} joined the room as {role:?}");
+                    }
+                }
+                // only the host answers, the player with the first handle
+                if let Some(Session::P2PSession(session)) = session.as_deref() {
+                    if session.local_player_handles().contains(&0) && !in_session(session, &peer) {
+                        match role {
+                            Role::Player => socket.send(GameMessage::MatchStarted, &peer),
+                            Role::Spectator => {
+                                if late_spectators.0.insert(peer.clone()) {
+                                    info!("streaming the match to late spectator {peer}");
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            GameMessage::Snapshot(snapshot) => {
+                if let Some(relayed) = relayed.as_mut() {
+                    relayed.snapshot = snapshot;
+                    relayed.timeout.reset();
+                    continue;
+                }
+                // the host never answers the spectator session of someone who turned up late
+                let waiting = match session.as_deref() {
+                    Some(Session::SpectatorSession(session)) => {
+                        session.current_state() == SessionState::Synchronizing
+                    }
+                    Some(_) => false,
+                    None => game_state.0 == GameState::Lobby,
+                };
+                let spectating = roles
+                    .as_ref()
+                    .map_or(false, |roles| roles.local == Role::Spectator);
+                if waiting && spectating {
+                    info!("{peer} streams us the match that started without us");
+                    commands.remove_resource::<Session<GGRSConfig>>();
+                    commands.insert_resource(RelayedMatch::new(snapshot));
+                    if game_state.0 == GameState::Lobby {
+                        commands.insert_resource(NextState(GameState::Ingame));
+                    }
+                }
+            }
+            GameMessage::MatchStarted => {
+                let synchronizing = match session.as_deref() {
+                    Some(Session::P2PSession(session)) => {
+                        session.current_state() == SessionState::Synchronizing
+                    }
+                    Some(Session::SpectatorSession(session)) => {
+                        session.current_state() == SessionState::Synchronizing
+                    }
+                    _ => false,
+                };
+                if synchronizing {
+                    warn!("{peer} started the match without us");
+                    commands.remove_resource::<Session<GGRSConfig>>();
+                    commands.insert_resource(PeerConnection::Lost {
+                        message: "This match started without you".to_string(),
+                        timer: Timer::new(MATCH_ENDED_DELAY, TimerMode::Once),
+                    });
+                }
+            }
+            GameMessage::Pong(clock) => {
+                if let Some(probe) = probe.as_mut() {
+                    let round_trip = ping_clock(&time).wrapping_sub(clock);
+                    probe
+                        .round_trips
+                        .entry(peer)
+                        .or_default()
+                        .push(Duration::from_millis(round_trip.into()));
+                }
+            }
+        }
+    }
+}
 
 fn lobby(
     mut commands: Commands,
-    mut socket: ResMut<Socket>,
+    socket: Res<MatchSocket>,
+    failure: Res<SocketFailure>,
+    args: Res<Args>,
+    room: Res<LobbyRoom>,
+    roles: Res<PeerRoles>,
     mut text_query: Query<&mut Text, With<LobbyText>>,
 ) {
-    let socket = socket.as_mut();
+    if let Some(reason) = failure.take() {
+        error!("lost the signalling server: {reason}");
+        commands.remove_resource::<MatchSocket>();
+        commands.insert_resource(LobbyError(format!(
+            "Could not connect to the signalling server:\n{reason}"
+        )));
+        return;
+    }
+
+    // every peer works out the lineup on its own, so they only agree once everyone in the room said
+    // what they are here for
+    let connected = socket.connected_peers();
+    if connected
+        .iter()
+        .any(|peer| !roles.remote.contains_key(peer))
+    {
+        return;
+    }
+    let remote_players = roles.peers(Role::Player, &connected);
+    let spectators = roles.peers(Role::Spectator, &connected);
+    let num_players = remote_players.len() + usize::from(roles.local == Role::Player);
+    let room_code = match room.as_ref() {
+        LobbyRoom::Public => String::new(),
+        LobbyRoom::Private(code) => format!("Room code: {code}\n\n"),
+        LobbyRoom::Spectate(code) => format!("Watching room: {code}\n\n"),
+    };
+    let watching = match spectators.len() {
+        0 => String::new(),
+        n => format!("\n{n} watching"),
+    };
+    text_query.single_mut().sections[0].value = format!(
+        "{room_code}Waiting for players {}/{}...{watching}",
+        num_players.min(NUM_PLAYERS),
+        NUM_PLAYERS
+    );
+    if num_players < NUM_PLAYERS {
+        return;
+    }
 
-    socket.as_mut().unwrap().accept_new_connections().len();
+    info!("Enough players in lobby, starting game.");
 
-    text_query.single_mut().sections[0].value = format!("Waiting for another player...");
+    if roles.local == Role::Spectator {
+        let host = match_lineup(remote_players, NUM_PLAYERS).remove(0);
+        start_spectator_session(&mut commands, &socket, &args, host);
+        return;
+    }
 
-    let num_connected = socket.as_ref().unwrap().connected_peers().len();
-    if num_connected == 0 {
+    let local = socket.id();
+    let mut lineup = remote_players;
+    lineup.push(local.clone());
+    let lineup = match_lineup(lineup, NUM_PLAYERS);
+    if !lineup.contains(&local) {
+        commands.insert_resource(LobbyError("This room is full".to_string()));
         return;
     }
 
-    info!("Enough players in lobby, starting game.");
+    // only the host sends the inputs to the spectators
+    let spectators = if lineup[0] == local {
+        spectators
+    } else {
+        Vec::new()
+    };
+    let players: Vec<PlayerType<String>> = lineup
+        .into_iter()
+        .map(|peer| {
+            if peer == local {
+                PlayerType::Local
+            } else {
+                PlayerType::Remote(peer)
+            }
+        })
+        .chain(spectators.into_iter().map(PlayerType::Spectator))
+        .collect();
+
+    match args.input_delay {
+        InputDelay::Fixed(input_delay) => {
+            start_session(&mut commands, &socket, &args, players, input_delay)
+        }
+        InputDelay::Auto => {
+            text_query.single_mut().sections[0].value = "Measuring latency...".to_string();
+            let remote_players = players
+                .iter()
+                .filter_map(|player| match player {
+                    PlayerType::Remote(peer) => Some(peer.clone()),
+                    _ => None,
+                })
+                .collect();
+            commands.insert_resource(LatencyProbe::new(remote_players));
+            commands.insert_resource(MatchLineup(players));
+        }
+    }
+}
+
+/// Players and spectators of the match about to start, in handle order
+#[derive(Resource)]
+struct MatchLineup(Vec<PlayerType<String>>);
+
+fn measure_latency(
+    mut commands: Commands,
+    time: Res<Time>,
+    socket: Res<MatchSocket>,
+    args: Res<Args>,
+    lineup: Res<MatchLineup>,
+    mut probe: ResMut<LatencyProbe>,
+) {
+    probe.timeout.tick(time.delta());
+    if probe.ping_timer.tick(time.delta()).just_finished() {
+        for peer in &probe.players {
+            socket.send(GameMessage::Ping(ping_clock(&time)), peer);
+        }
+    }
 
-    let socket = socket.take().unwrap();
+    let measured = probe
+        .players
+        .iter()
+        .all(|peer| match probe.round_trips.get(peer) {
+            Some(round_trips) => round_trips.len() >= PING_SAMPLES,
+            None => false,
+        });
+    if !measured && !probe.timeout.finished() {
+        return;
+    }
 
-    let players = socket.players();
+    for (peer, round_trips) in &probe.round_trips {
+        info!("round trips to {peer}: {round_trips:?}");
+    }
+    let input_delay = auto_input_delay(probe.round_trips.values(), args.fps);
+    start_session(&mut commands, &socket, &args, lineup.0.clone(), input_delay);
+}
 
-    let max_prediction = 12;
+fn start_session(
+    commands: &mut Commands,
+    socket: &MatchSocket,
+    args: &Args,
+    players: Vec<PlayerType<String>>,
+    input_delay: usize,
+) {
+    info!("starting P2P session with an input delay of {input_delay} frames");
 
     // create a GGRS P2P session
     let mut sess_build = SessionBuilder::<GGRSConfig>::new()
-        .with_num_players(2)
-        .with_max_prediction_window(max_prediction)
-        .with_input_delay(2)
-        .with_fps(60)
+        .with_num_players(NUM_PLAYERS)
+        .with_max_prediction_window(args.max_prediction)
+        .with_input_delay(input_delay)
+        .with_disconnect_notify_delay(Duration::from_secs(1))
+        .with_disconnect_timeout(DISCONNECT_TIMEOUT)
+        .with_fps(args.fps)
         .expect("Invalid fps.");
 
+    let mut spectators = HashMap::default();
     for (i, player) in players.into_iter().enumerate() {
+        if let PlayerType::Spectator(peer) = &player {
+            spectators.insert(peer.clone(), i);
+        }
         sess_build = sess_build
             .add_player(player, i)
             .expect("Failed to add player.");
@@ -130,173 +872,1555 @@ fn lobby(
 
     // start the GGRS session
     let sess = sess_build
-        .start_p2p_session(socket)
+        .start_p2p_session(socket.clone())
         .expect("Failed to add player.");
 
     commands.insert_resource(Session::P2PSession(sess));
+    if !spectators.is_empty() {
+        commands.insert_resource(PendingSpectators {
+            handles: spectators,
+            timer: Timer::new(SPECTATOR_SYNC_TIMEOUT, TimerMode::Once),
+        });
+    }
 
     commands.insert_resource(NextState(GameState::Ingame));
 }
 
-const INPUT_UP: u8 = 1 << 0;
-const INPUT_DOWN: u8 = 1 << 1;
-const INPUT_SERVE: u8 = 1 << 2;
+/// How long the host waits for its spectators to synchronize before starting without them
+const SPECTATOR_SYNC_TIMEOUT: Duration = Duration::from_secs(5);
 
-fn input(_handle: In<PlayerHandle>, keyboard_input: Res<Input<KeyCode>>) -> BoxInput {
-    let mut input: u8 = 0;
+/// Spectators the host's session still waits for, GGRS only starts the match once every
+/// spectator synchronized, by peer id
+#[derive(Resource)]
+struct PendingSpectators {
+    handles: HashMap<String, PlayerHandle>,
+    timer: Timer,
+}
 
-    if keyboard_input.pressed(KeyCode::W) || keyboard_input.pressed(KeyCode::Up) {
-        input |= INPUT_UP;
+/// Stop waiting for spectators that left before the match started, or it never would
+fn drop_pending_spectators(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut pending: ResMut<PendingSpectators>,
+    mut session: ResMut<Session<GGRSConfig>>,
+    mut late_spectators: ResMut<LateSpectators>,
+) {
+    if !pending.timer.tick(time.delta()).finished() && !pending.handles.is_empty() {
+        return;
     }
-    if keyboard_input.pressed(KeyCode::S) || keyboard_input.pressed(KeyCode::Down) {
-        input |= INPUT_DOWN;
+    commands.remove_resource::<PendingSpectators>();
+
+    let Session::P2PSession(session) = session.as_mut() else {
+        return;
+    };
+    for (peer, &handle) in &pending.handles {
+        warn!("spectator {peer} did not synchronize, streaming the match to it instead");
+        if let Err(err) = session.disconnect_player(handle) {
+            error!("failed to drop spectator {peer}: {err}");
+        }
+        late_spectators.0.insert(peer.clone());
     }
-    if keyboard_input.pressed(KeyCode::Space) {
-        input |= INPUT_SERVE;
+}
+
+fn start_spectator_session(
+    commands: &mut Commands,
+    socket: &MatchSocket,
+    args: &Args,
+    host: String,
+) {
+    info!("starting spectator session with host {host}");
+
+    let sess = SessionBuilder::<GGRSConfig>::new()
+        .with_num_players(NUM_PLAYERS)
+        .with_max_prediction_window(args.max_prediction)
+        .with_disconnect_notify_delay(Duration::from_secs(1))
+        .with_disconnect_timeout(DISCONNECT_TIMEOUT)
+        .with_fps(args.fps)
+        .expect("Invalid fps.")
+        .start_spectator_session(host, socket.clone());
+
+    commands.insert_resource(Session::SpectatorSession(sess));
+
+    commands.insert_resource(NextState(GameState::Ingame));
+}
+
+/// Spectators that turned up after the match started, by peer id. Only the host has any, it sends
+/// them a snapshot of the match every frame.
+#[derive(Resource, Debug, Default)]
+struct LateSpectators(HashSet<String>);
+
+/// Watching a match through the snapshots its host streams, for spectators that turned up after it
+/// started
+#[derive(Resource, Debug)]
+struct RelayedMatch {
+    snapshot: MatchSnapshot,
+    /// Runs out when nothing came from the host for a while
+    timeout: Timer,
+}
+
+impl RelayedMatch {
+    fn new(snapshot: MatchSnapshot) -> Self {
+        RelayedMatch {
+            snapshot,
+            timeout: Timer::new(DISCONNECT_TIMEOUT, TimerMode::Once),
+        }
     }
+}
 
-    BoxInput { inp: input }
+/// The match as a late spectator gets to see it, `None` before the court is built
+fn snapshot_match(
+    ball_query: Query<&Position, With<Ball>>,
+    paddle_query: Query<(&Position, Option<&LeftPlayer>), With<Paddle>>,
+    (score, clock, pong_state): (Res<Score>, Res<MatchClock>, Res<PongState>),
+) -> Option<MatchSnapshot> {
+    let ball = ball_query.get_single().ok()?;
+    let mut snapshot = MatchSnapshot {
+        ball: ball.to_vec2(),
+        left_paddle: Vec2::ZERO,
+        right_paddle: Vec2::ZERO,
+        score: [score.left, score.right, score.left_games, score.right_games].map(|n| n as u32),
+        clock: clock.elapsed,
+        state: *pong_state,
+    };
+    for (position, left) in &paddle_query {
+        match left {
+            Some(_) => snapshot.left_paddle = position.to_vec2(),
+            None => snapshot.right_paddle = position.to_vec2(),
+        }
+    }
+    Some(snapshot)
 }
-#[derive(Resource, Deref, DerefMut)]
-struct BoxInputs(Vec<(BoxInput, InputStatus)>);
 
-fn box_input_to_paddle_input(
-    inputs: Res<PlayerInputs<GGRSConfig>>,
-    mut paddle_inputs: ResMut<PaddleInputs>,
+fn stream_match(
+    In(snapshot): In<Option<MatchSnapshot>>,
+    socket: Res<MatchSocket>,
+    mut late_spectators: ResMut<LateSpectators>,
+) {
+    let connected = socket.connected_peers();
+    late_spectators.0.retain(|peer| connected.contains(peer));
+    let Some(snapshot) = snapshot else {
+        return;
+    };
+    for peer in &late_spectators.0 {
+        socket.send(GameMessage::Snapshot(snapshot), peer);
+    }
+}
+
+/// Show the match as the host last streamed it, until the host goes quiet
+fn follow_relayed_match(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut relayed: ResMut<RelayedMatch>,
+    mut position_query: Query<(&mut Position, Option<&Ball>, Option<&LeftPlayer>)>,
+    (mut score, mut clock, mut pong_state): (ResMut<Score>, ResMut<MatchClock>, ResMut<PongState>),
 ) {
-    for i in 0..2 {
-        let input = inputs[i].0.inp;
-        paddle_inputs[i] = PaddleInput {
-            move_up: input & INPUT_UP != 0,
-            move_down: input & INPUT_DOWN != 0,
-            serve: input & INPUT_SERVE != 0,
+    if relayed.timeout.tick(time.delta()).just_finished() {
+        commands.insert_resource(PeerConnection::Lost {
+            message: "The host left\nMatch over".to_string(),
+            timer: Timer::new(MATCH_ENDED_DELAY, TimerMode::Once),
+        });
+    }
+
+    let snapshot = relayed.snapshot;
+    for (mut position, ball, left) in &mut position_query {
+        let target = match (ball, left) {
+            (Some(_), _) => snapshot.ball,
+            (None, Some(_)) => snapshot.left_paddle,
+            (None, None) => snapshot.right_paddle,
+        };
+        let target = FixedVec2::from_vec2(target);
+        if **position != target {
+            **position = target;
         }
     }
+    let [left, right, left_games, right_games] = snapshot.score.map(|n| n as usize);
+    *score = Score {
+        left,
+        right,
+        left_games,
+        right_games,
+    };
+    clock.elapsed = snapshot.clock;
+    if *pong_state != snapshot.state {
+        *pong_state = snapshot.state;
+    }
 }
 
-fn log_ggrs_events(mut session: ResMut<Session<GGRSConfig>>) {
-    match session.as_mut() {
-        Session::P2PSession(s) => {
-            for event in s.events() {
-                info!("GGRS Event: {:?}", event);
+const INPUT_SERVE: u8 = 1 << 0;
+/// Set for as long as the player wants a rematch, the match restarts once every player sets it
+const INPUT_REMATCH: u8 = 1 << 1;
+
+/// Which paddle each player controls, the same on every peer. Handle 0 starts on the left and the
+/// sides swap with every rematch.
+#[derive(Resource, Debug, Default, Hash, Reflect)]
+#[reflect(Resource, Hash)]
+pub(crate) struct SideAssignment {
+    pub(crate) rematches: u32,
+}
+
+impl SideAssignment {
+    pub(crate) fn handle(&self, side: Side) -> PlayerHandle {
+        let first = match side {
+            Side::Left => 0,
+            Side::Right => 1,
+        };
+        (first + self.rematches as usize) % 2
+    }
+
+    pub(crate) fn side(&self, handle: PlayerHandle) -> Option<Side> {
+        [Side::Left, Side::Right]
+            .into_iter()
+            .find(|&side| self.handle(side) == handle)
+    }
+}
+
+/// Read the input the session sends for a player at this machine from the controller of the
+/// paddle the player has
+fn input(
+    handle: In<PlayerHandle>,
+    mut paddle_query: Query<(&Paddle, &Position, &Collider, &mut PaddleController)>,
+    sources: ControllerSources,
+    args: Res<Args>,
+    gamepads: Res<Gamepads>,
+    rematch: Res<RematchRequested>,
+) -> BoxInput {
+    let mut paddle_input = PaddleInput::default();
+    for (paddle, position, collider, mut controller) in &mut paddle_query {
+        let PaddleController::Network { local } = &mut *controller else {
+            continue;
+        };
+        if paddle.handle != handle.0 {
+            continue;
+        }
+        // the first gamepad plays alongside the keyboard, unless every player is at this machine
+        if let PaddleController::Player(controls) = local.as_mut() {
+            if !args.synctest {
+                controls.gamepad = gamepads.iter().min_by_key(|gamepad| gamepad.id);
             }
         }
-        _ => {}
+        if let Some(input) = local.input((paddle, position, collider), &sources) {
+            paddle_input = input;
+        }
+    }
+
+    let mut input: u8 = 0;
+    if paddle_input.serve {
+        input |= INPUT_SERVE;
+    }
+    if rematch.0 {
+        input |= INPUT_REMATCH;
+    }
+
+    BoxInput {
+        inp: input,
+        axis: paddle_input.axis,
     }
 }
 
-fn setup_online_player_controllers(
+impl From<BoxInput> for PaddleInput {
+    fn from(input: BoxInput) -> Self {
+        PaddleInput {
+            axis: input.axis,
+            serve: input.inp & INPUT_SERVE != 0,
+        }
+    }
+}
+
+/// Start the next match on the same session once every player asked for a rematch. The request
+/// travels with the inputs, so every peer resets on the same frame, and a rollback past that frame
+/// undoes the reset like any other part of the match.
+fn rematch(
     mut commands: Commands,
-    mut rip: ResMut<RollbackIdProvider>,
-    mut paddle_set: ParamSet<(
-        Query<Entity, (Added<Paddle>, With<LeftPlayer>)>,
-        Query<Entity, (Added<Paddle>, With<RightPlayer>)>,
-    )>,
+    inputs: Res<PlayerInputs<GGRSConfig>>,
+    config: Res<PongConfig>,
+    mut sides: ResMut<SideAssignment>,
+    pong_state: Res<PongState>,
+    mut paddle_query: Query<(&mut Paddle, &mut Position, Option<&LeftPlayer>), Without<Ball>>,
+    mut ball_query: Query<(&mut Ball, &mut Position), Without<Paddle>>,
 ) {
-    if let Ok(left_paddle) = paddle_set.p0().get_single() {
-        commands
-            .entity(left_paddle)
-            .insert(Rollback::new(rip.next_id()));
+    if !matches!(*pong_state, PongState::GameOver(_))
+        || !inputs
+            .iter()
+            .all(|(input, _)| input.inp & INPUT_REMATCH != 0)
+    {
+        return;
     }
 
-    if let Ok(right_paddle) = paddle_set.p1().get_single() {
-        commands
-            .entity(right_paddle)
-            .insert(Rollback::new(rip.next_id()));
+    sides.rematches += 1;
+    reset_match_state(&mut commands);
+    reset_court(&config, &mut paddle_query, &mut ball_query);
+    for (mut paddle, _, left) in &mut paddle_query {
+        let side = if left.is_some() {
+            Side::Left
+        } else {
+            Side::Right
+        };
+        paddle.handle = sides.handle(side);
     }
 }
 
-const ROLLBACK_DEFAULT: &str = "rollback_default";
-pub(crate) struct OnlinePlugin;
+/// How long a peer can go quiet before it counts as disconnected
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the outcome of a match that ended early stays up before going back to the menu
+const MATCH_ENDED_DELAY: Duration = Duration::from_secs(5);
 
-impl Plugin for OnlinePlugin {
-    fn build(&self, app: &mut App) {
-        GGRSPlugin::<GGRSConfig>::new()
-            .with_update_frequency(60)
-            .with_input_system(input)
-            .register_rollback_component::<Transform>()
-            .register_rollback_component::<Ball>()
-            .register_rollback_component::<Paddle>()
-            .with_rollback_schedule(
-                Schedule::default().with_stage(
-                    ROLLBACK_DEFAULT,
-                    SystemStage::parallel()
-                        .with_system(box_input_to_paddle_input.before(GameloopStage::Input))
-                        .with_system(
-                            serve::<LeftPlayer>
-                                .run_in_state(GameType::Online)
-                                .run_in_state(GameState::Ingame)
-                                .run_in_state(PongState::Serve(Side::Left))
-                                .label(GameloopStage::Input),
-                        )
-                        .with_system(
-                            serve::<RightPlayer>
-                                .run_in_state(GameType::Online)
-                                .run_in_state(GameState::Ingame)
-                                .run_in_state(PongState::Serve(Side::Right))
-                                .label(GameloopStage::Input),
-                        )
-                        .with_system(
-                            paddle_movement
-                                .run_in_state(GameType::Online)
-                                .run_in_state(GameState::Ingame)
-                                .after(GameloopStage::Input)
-                                .label(GameloopStage::Movement),
-                        )
-                        .with_system(
-                            ball_movement
-                                .run_in_state(GameType::Online)
-                                .run_in_state(GameState::Ingame)
-                                .run_in_state(PongState::Playing)
-                                .after(GameloopStage::Input)
-                                .label(GameloopStage::Movement),
-                        )
-                        .with_system(
-                            ball_collision
-                                .run_in_state(GameType::Online)
-                                .run_in_state(GameState::Ingame)
-                                .run_in_state(PongState::Playing)
-                                .after(GameloopStage::Movement)
-                                .label(GameloopStage::Collision),
-                        )
-                        .with_system(
-                            ball_scoring
-                                .run_in_state(GameType::Online)
-                                .run_in_state(GameState::Ingame)
-                                .run_in_state(PongState::Playing)
-                                .after(GameloopStage::Collision)
-                                .label(GameloopStage::Scoring),
-                        )
-                        .with_system(
-                            scoreboard
-                                .run_in_state(GameType::Online)
-                                .run_in_state(GameState::Ingame)
-                                .after(GameloopStage::Scoring),
-                        ),
-                ),
-            )
-            .build(app);
+/// Trouble with the connection to the other player, shown on top of the game
+#[derive(Resource, Debug)]
+enum PeerConnection {
+    /// Nothing heard from the peer for a while, it is disconnected when the timer runs out
+    Interrupted(Timer),
+    /// The peer is gone and the session closed, back to the menu when the timer runs out
+    Lost { message: String, timer: Timer },
+}
 
-        app.add_enter_system_set(
-            GameState::Lobby,
-            ConditionSet::new()
-                .with_system(start_matchbox_socket)
-                .with_system(setup_lobby)
-                .into(),
-        )
-        .add_exit_system(GameState::Lobby, lobby_cleanup)
-        .add_system(lobby.run_in_state(GameState::Lobby))
-        .add_system_set(
-            ConditionSet::new()
-                .run_in_state(GameType::Online)
-                .run_in_state(GameState::Ingame)
-                .with_system(log_ggrs_events)
-                .into(),
-        )
-        .add_system(
-            setup_online_player_controllers
-                .run_in_state(GameType::Online)
-                .run_in_state(GameState::Ingame),
+#[derive(Component)]
+struct ConnectionOverlay;
+
+#[derive(Component)]
+struct ConnectionOverlayText;
+
+/// What becomes of the match when the opponent leaves: it is awarded to the player that stayed if
+/// they were ahead, in games and then in points, otherwise it is abandoned. A match that is already
+/// over keeps its outcome.
+fn match_outcome_without_opponent(
+    score: &Score,
+    pong_state: &PongState,
+    local_side: Option<Side>,
+) -> &'static str {
+    if matches!(pong_state, PongState::GameOver(_)) {
+        return "Opponent left";
+    }
+    let left = (score.left_games, score.left);
+    let right = (score.right_games, score.right);
+    let ahead = match local_side {
+        Some(Side::Left) => left > right,
+        Some(Side::Right) => right > left,
+        None => false,
+    };
+    if ahead {
+        "Opponent left\nYou win!"
+    } else {
+        "Opponent left\nMatch abandoned"
+    }
+}
+
+/// Side of the court played at this machine, `None` when only watching
+fn local_side(session: &Session<GGRSConfig>, sides: &SideAssignment) -> Option<Side> {
+    match session {
+        Session::P2PSession(session) => session
+            .local_player_handles()
+            .first()
+            .and_then(|&handle| sides.side(handle)),
+        _ => None,
+    }
+}
+
+/// Address of the peer an event is about
+fn event_addr(event: &GGRSEvent<GGRSConfig>) -> Option<&String> {
+    match event {
+        GGRSEvent::Synchronizing { addr, .. }
+        | GGRSEvent::Synchronized { addr }
+        | GGRSEvent::Disconnected { addr }
+        | GGRSEvent::NetworkInterrupted { addr, .. }
+        | GGRSEvent::NetworkResumed { addr } => Some(addr),
+        GGRSEvent::WaitRecommendation { .. } => None,
+    }
+}
+
+fn handle_ggrs_events(
+    mut commands: Commands,
+    mut session: ResMut<Session<GGRSConfig>>,
+    mut pending_spectators: Option<ResMut<PendingSpectators>>,
+    (score, pong_state, sides): (Res<Score>, Res<PongState>, Res<SideAssignment>),
+) {
+    let local_side = local_side(&session, &sides);
+    let (events, spectating): (Vec<_>, _) = match session.as_mut() {
+        Session::P2PSession(s) => {
+            let events: Vec<_> = s.events().collect();
+            // spectators come and go without affecting the match
+            let num_players = s.num_players();
+            let events = events
+                .into_iter()
+                .filter(|event| {
+                    let Some(addr) = event_addr(event) else {
+                        return true;
+                    };
+                    let handles = s.handles_by_address(addr.clone());
+                    if handles.iter().all(|&handle| handle >= num_players) {
+                        info!("GGRS spectator event: {:?}", event);
+                        if let (GGRSEvent::Synchronized { addr }, Some(pending)) =
+                            (event, pending_spectators.as_mut())
+                        {
+                            pending.handles.remove(addr);
+                        }
+                        return false;
+                    }
+                    true
+                })
+                .collect();
+            (events, false)
+        }
+        Session::SpectatorSession(s) => (s.events().collect(), true),
+        Session::SyncTestSession(_) => return,
+    };
+
+    for event in events {
+        info!("GGRS Event: {:?}", event);
+        match event {
+            GGRSEvent::NetworkInterrupted {
+                disconnect_timeout, ..
+            } => {
+                commands.insert_resource(PeerConnection::Interrupted(Timer::new(
+                    Duration::from_millis(disconnect_timeout as u64),
+                    TimerMode::Once,
+                )));
+            }
+            GGRSEvent::NetworkResumed { .. } => {
+                commands.remove_resource::<PeerConnection>();
+            }
+            GGRSEvent::Disconnected { .. } => {
+                // dropping the session stops the simulation, the game stays frozen under the
+                // overlay. The socket stays open until close_socket runs back in the main menu.
+                commands.remove_resource::<Session<GGRSConfig>>();
+                let message = if spectating {
+                    "The host left\nMatch over"
+                } else {
+                    match_outcome_without_opponent(&score, &pong_state, local_side)
+                };
+                commands.insert_resource(PeerConnection::Lost {
+                    message: message.to_string(),
+                    timer: Timer::new(MATCH_ENDED_DELAY, TimerMode::Once),
+                });
+                return;
+            }
+            _ => (),
+        }
+    }
+}
+
+fn tick_peer_connection(
+    mut commands: Commands,
+    time: Res<Time>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut peer_connection: ResMut<PeerConnection>,
+) {
+    match peer_connection.as_mut() {
+        PeerConnection::Interrupted(timer) => {
+            timer.tick(time.delta());
+        }
+        PeerConnection::Lost { timer, .. } => {
+            timer.tick(time.delta());
+            if timer.finished() || keyboard_input.just_pressed(KeyCode::Escape) {
+                commands.insert_resource(NextState(GameState::MainMenu));
+            }
+        }
+    }
+}
+
+fn connection_overlay(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    peer_connection: Option<Res<PeerConnection>>,
+    overlay_query: Query<Entity, With<ConnectionOverlay>>,
+    mut text_query: Query<&mut Text, With<ConnectionOverlayText>>,
+) {
+    let Some(peer_connection) = peer_connection else {
+        for overlay in &overlay_query {
+            commands.entity(overlay).despawn_recursive();
+        }
+        return;
+    };
+
+    let message = match peer_connection.as_ref() {
+        PeerConnection::Interrupted(timer) => format!(
+            "Connection interrupted\nDisconnecting in {}",
+            timer.remaining_secs().ceil()
+        ),
+        PeerConnection::Lost { message, timer } => format!(
+            "{message}\n\nBack to the menu in {}",
+            timer.remaining_secs().ceil()
+        ),
+    };
+
+    if let Ok(mut text) = text_query.get_single_mut() {
+        text.sections[0].value = message;
+        return;
+    }
+
+    commands
+        .spawn((
+            ConnectionOverlay,
+            NodeBundle {
+                style: Style {
+                    size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                    position_type: PositionType::Absolute,
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                background_color: Color::rgba(0., 0., 0., 0.7).into(),
+                z_index: ZIndex::Global(1),
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                ConnectionOverlayText,
+                TextBundle::from_section(
+                    message,
+                    TextStyle {
+                        font: asset_server.load("fonts/PublicPixel-z84yD.ttf"),
+                        font_size: 40.,
+                        ..default()
+                    },
+                )
+                .with_text_alignment(TextAlignment::CENTER),
+            ));
+        });
+}
+
+/// Close whatever is left of the session when leaving the game
+fn online_cleanup(mut commands: Commands) {
+    commands.remove_resource::<Session<GGRSConfig>>();
+    commands.insert_resource(SideAssignment::default());
+    commands.remove_resource::<PeerConnection>();
+    commands.remove_resource::<PendingSpectators>();
+    commands.insert_resource(LateSpectators::default());
+    commands.remove_resource::<RelayedMatch>();
+    commands.insert_resource(RollbackFrame::default());
+    commands.insert_resource(RollbackCounter::default());
+    commands.remove_resource::<NetworkStatsTimer>();
+    commands.insert_resource(SideBannerShown::default());
+    commands.insert_resource(RematchRequested::default());
+}
+
+/// How long the side of the local player is pointed out at the start of a match
+const SIDE_BANNER_TIME: Duration = Duration::from_secs(3);
+/// Tint of the local paddle while the side banner is up
+const LOCAL_PADDLE_HIGHLIGHT: Color = Color::rgb(1.0, 0.8, 0.2);
+
+/// "You are LEFT/RIGHT" banner, fading out together with the highlight on the local paddle
+#[derive(Component)]
+struct SideBanner {
+    side: Side,
+    timer: Timer,
+}
+
+/// Match of the session the side banner was last shown for, counted in rematches
+#[derive(Resource, Debug, Default)]
+struct SideBannerShown(Option<u32>);
+
+fn show_side_banner(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    session: Option<Res<Session<GGRSConfig>>>,
+    sides: Res<SideAssignment>,
+    mut shown: ResMut<SideBannerShown>,
+) {
+    let Some(Session::P2PSession(session)) = session.as_deref() else {
+        return;
+    };
+    if shown.0 == Some(sides.rematches) {
+        return;
+    }
+    shown.0 = Some(sides.rematches);
+    let Some(side) = session
+        .local_player_handles()
+        .first()
+        .and_then(|&handle| sides.side(handle))
+    else {
+        return;
+    };
+    info!("local player plays on the {side:?} side");
+
+    let label = match side {
+        Side::Left => "You are LEFT",
+        Side::Right => "You are RIGHT",
+    };
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                position_type: PositionType::Absolute,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::FlexStart,
+                ..default()
+            },
+            ..default()
+        })
+        .insert(SideBanner {
+            side,
+            timer: Timer::new(SIDE_BANNER_TIME, TimerMode::Once),
+        })
+        .with_children(|parent| {
+            parent.spawn(
+                TextBundle::from_section(
+                    label,
+                    TextStyle {
+                        font: asset_server.load("fonts/PublicPixel-z84yD.ttf"),
+                        font_size: 40.,
+                        color: LOCAL_PADDLE_HIGHLIGHT,
+                    },
+                )
+                .with_style(Style {
+                    margin: UiRect::bottom(Val::Percent(15.)),
+                    ..default()
+                }),
+            );
+        });
+}
+
+fn fade_side_banner(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut banner_query: Query<(Entity, &mut SideBanner, &Children)>,
+    mut text_query: Query<&mut Text>,
+    mut paddle_query: Query<(&mut Sprite, Option<&LeftPlayer>), With<Paddle>>,
+) {
+    for (entity, mut banner, children) in &mut banner_query {
+        banner.timer.tick(time.delta());
+        let fade = banner.timer.percent_left();
+
+        for &child in children {
+            if let Ok(mut text) = text_query.get_mut(child) {
+                text.sections[0].style.color.set_a(fade);
+            }
+        }
+
+        let highlight = Vec4::from(LOCAL_PADDLE_HIGHLIGHT).lerp(Vec4::ONE, 1. - fade);
+        for (mut sprite, left) in &mut paddle_query {
+            let side = if left.is_some() {
+                Side::Left
+            } else {
+                Side::Right
+            };
+            if side == banner.side {
+                sprite.color = highlight.into();
+            }
+        }
+
+        if banner.timer.finished() {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Shown for as long as we're watching someone else's match
+#[derive(Component)]
+struct SpectatorBanner;
+
+fn show_spectator_banner(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    session: Option<Res<Session<GGRSConfig>>>,
+    relayed: Option<Res<RelayedMatch>>,
+) {
+    if !matches!(session.as_deref(), Some(Session::SpectatorSession(_))) && relayed.is_none() {
+        return;
+    }
+
+    commands
+        .spawn((
+            SpectatorBanner,
+            NodeBundle {
+                style: Style {
+                    size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                    position_type: PositionType::Absolute,
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::FlexStart,
+                    ..default()
+                },
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn(
+                TextBundle::from_section(
+                    "SPECTATING\nEscape to leave",
+                    TextStyle {
+                        font: asset_server.load("fonts/PublicPixel-z84yD.ttf"),
+                        font_size: 20.,
+                        color: Color::GRAY,
+                    },
+                )
+                .with_text_alignment(TextAlignment::CENTER)
+                .with_style(Style {
+                    margin: UiRect::bottom(Val::Px(20.)),
+                    ..default()
+                }),
+            );
+        });
+}
+
+/// Spectators can leave whenever they like, the players never wait for them
+fn leave_spectating(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    session: Option<Res<Session<GGRSConfig>>>,
+    relayed: Option<Res<RelayedMatch>>,
+) {
+    let spectating =
+        matches!(session.as_deref(), Some(Session::SpectatorSession(_))) || relayed.is_some();
+    if spectating && keyboard_input.just_pressed(KeyCode::Escape) {
+        commands.insert_resource(NextState(GameState::MainMenu));
+    }
+}
+
+/// Whether the local player asked for a rematch, sent along with every input until it starts
+#[derive(Resource, Debug, Default)]
+struct RematchRequested(bool);
+
+/// Outcome of a finished match with the choice between a rematch and leaving
+#[derive(Component)]
+struct PostMatchScreen;
+
+/// Tells the player whether the other side wants a rematch too
+#[derive(Component)]
+struct PostMatchStatus;
+
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+enum PostMatchButton {
+    Rematch,
+    Leave,
+}
+
+fn post_match_screen(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    pong_state: Res<PongState>,
+    session: Option<Res<Session<GGRSConfig>>>,
+    sides: Res<SideAssignment>,
+    mut rematch: ResMut<RematchRequested>,
+    screen_query: Query<Entity, With<PostMatchScreen>>,
+) {
+    let PongState::GameOver(winner) = *pong_state else {
+        // the rematch started
+        for screen in &screen_query {
+            commands.entity(screen).despawn_recursive();
+        }
+        rematch.0 = false;
+        return;
+    };
+    if !screen_query.is_empty() {
+        return;
+    }
+
+    let local_side = session
+        .as_deref()
+        .and_then(|session| local_side(session, &sides));
+    let outcome = match (local_side, winner) {
+        (Some(side), winner) if side == winner => "You win!",
+        (Some(_), _) => "You lose!",
+        (None, Side::Left) => "Left player wins!",
+        (None, Side::Right) => "Right player wins!",
+    };
+    // late spectators watch without a session
+    let buttons: &[PostMatchButton] = match session.as_deref() {
+        Some(Session::SpectatorSession(_)) | None => &[PostMatchButton::Leave],
+        _ => &[PostMatchButton::Rematch, PostMatchButton::Leave],
+    };
+
+    let font = asset_server.load("fonts/PublicPixel-z84yD.ttf");
+    let text_style = TextStyle {
+        font: font.clone(),
+        font_size: 32.,
+        ..default()
+    };
+    commands
+        .spawn((
+            PostMatchScreen,
+            NodeBundle {
+                style: Style {
+                    size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                    position_type: PositionType::Absolute,
+                    flex_direction: FlexDirection::ColumnReverse,
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                background_color: Color::rgba(0., 0., 0., 0.6).into(),
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn(TextBundle::from_section(
+                outcome,
+                TextStyle {
+                    font_size: 40.,
+                    ..text_style.clone()
+                },
+            ));
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    ..default()
+                })
+                .with_children(|parent| {
+                    for &button in buttons {
+                        let label = match button {
+                            PostMatchButton::Rematch => "Rematch",
+                            PostMatchButton::Leave => "Leave",
+                        };
+                        parent
+                            .spawn((
+                                button,
+                                ButtonBundle {
+                                    style: Style {
+                                        margin: UiRect::all(Val::Px(40.)),
+                                        justify_content: JustifyContent::Center,
+                                        align_items: AlignItems::Center,
+                                        ..default()
+                                    },
+                                    background_color: Color::BLACK.into(),
+                                    ..default()
+                                },
+                            ))
+                            .with_children(|parent| {
+                                parent.spawn(TextBundle::from_section(label, text_style.clone()));
+                            });
+                    }
+                });
+            parent.spawn((
+                PostMatchStatus,
+                TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font,
+                        font_size: 16.,
+                        color: Color::GRAY,
+                    },
+                )
+                .with_text_alignment(TextAlignment::CENTER),
+            ));
+        });
+}
+
+fn match_over(pong_state: Res<PongState>) -> bool {
+    matches!(*pong_state, PongState::GameOver(_))
+}
+
+/// Enter or the Rematch button asks for a rematch, Escape or the Leave button goes back to the menu
+fn post_match_buttons(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    session: Option<Res<Session<GGRSConfig>>>,
+    mut rematch: ResMut<RematchRequested>,
+    interaction_query: Query<(&Interaction, &PostMatchButton), Changed<Interaction>>,
+) {
+    let clicked = |wanted| {
+        interaction_query
+            .iter()
+            .any(|(interaction, &button)| *interaction == Interaction::Clicked && button == wanted)
+    };
+
+    if keyboard_input.just_pressed(KeyCode::Escape) || clicked(PostMatchButton::Leave) {
+        commands.insert_resource(NextState(GameState::MainMenu));
+    } else if (keyboard_input.just_pressed(KeyCode::Return) || clicked(PostMatchButton::Rematch))
+        && !matches!(
+            session.as_deref(),
+            Some(Session::SpectatorSession(_)) | None
+        )
+    {
+        rematch.0 = true;
+    }
+}
+
+fn post_match_status(
+    session: Res<Session<GGRSConfig>>,
+    rematch: Res<RematchRequested>,
+    inputs: Option<Res<PlayerInputs<GGRSConfig>>>,
+    mut text_query: Query<&mut Text, With<PostMatchStatus>>,
+) {
+    let Session::P2PSession(session) = session.as_ref() else {
+        return;
+    };
+    let local_handles = session.local_player_handles();
+    let opponent_wants_rematch = match inputs {
+        Some(inputs) => inputs.iter().enumerate().any(|(handle, (input, _))| {
+            !local_handles.contains(&handle) && input.inp & INPUT_REMATCH != 0
+        }),
+        None => false,
+    };
+
+    let status = match (rematch.0, opponent_wants_rematch) {
+        (true, _) => "Waiting for your opponent...",
+        (false, true) => "Your opponent wants a rematch",
+        (false, false) => "",
+    };
+    for mut text in &mut text_query {
+        if text.sections[0].value != status {
+            text.sections[0].value = status.to_string();
+        }
+    }
+}
+
+#[derive(Component)]
+struct NetworkStatsText;
+
+/// Polls the network stats of the session every second while in game
+#[derive(Resource)]
+struct NetworkStatsTimer {
+    timer: Timer,
+    /// Rollback count at the previous poll
+    rollbacks: u32,
+}
+
+impl Default for NetworkStatsTimer {
+    fn default() -> Self {
+        NetworkStatsTimer {
+            timer: Timer::from_seconds(1., TimerMode::Repeating),
+            rollbacks: 0,
+        }
+    }
+}
+
+/// Online matches tick at the rate every player agreed on
+fn insert_timestep(mut commands: Commands, args: Res<Args>) {
+    commands.insert_resource(Timestep(Duration::from_secs(1) / args.fps as u32));
+}
+
+fn setup_network_stats(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.init_resource::<NetworkStatsTimer>();
+    commands.spawn((
+        NetworkStatsText,
+        TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(10.),
+                    top: Val::Px(10.),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text::from_section(
+                "Waiting for network stats...",
+                TextStyle {
+                    font: asset_server.load("fonts/PublicPixel-z84yD.ttf"),
+                    font_size: 14.,
+                    ..default()
+                },
+            ),
+            visibility: Visibility { is_visible: false },
+            ..default()
+        },
+    ));
+}
+
+fn toggle_network_stats(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut visibility_query: Query<&mut Visibility, With<NetworkStatsText>>,
+) {
+    if keyboard_input.just_pressed(KeyCode::F3) {
+        for mut visibility in &mut visibility_query {
+            visibility.is_visible = !visibility.is_visible;
+        }
+    }
+}
+
+fn poll_network_stats(
+    time: Res<Time>,
+    mut stats_timer: ResMut<NetworkStatsTimer>,
+    session: Res<Session<GGRSConfig>>,
+    counter: Res<RollbackCounter>,
+    mut text_query: Query<&mut Text, With<NetworkStatsText>>,
+) {
+    if !stats_timer.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let rollbacks = counter.rollbacks - stats_timer.rollbacks;
+    stats_timer.rollbacks = counter.rollbacks;
+
+    let Session::P2PSession(session) = session.as_ref() else {
+        return;
+    };
+
+    let mut lines = Vec::new();
+    for handle in session.remote_player_handles() {
+        match session.network_stats(handle) {
+            Ok(stats) => lines.push(format!(
+                "player {handle}\nping {} ms\nsent {} kbps\nsend queue {}\n\
+                 frames behind {} local, {} remote",
+                stats.ping,
+                stats.kbps_sent,
+                stats.send_queue_len,
+                stats.local_frames_behind,
+                stats.remote_frames_behind
+            )),
+            Err(err) => lines.push(format!("player {handle}\n{err}")),
+        }
+    }
+    lines.push(format!("rollbacks {rollbacks}/s"));
+
+    for mut text in &mut text_query {
+        text.sections[0].value = lines.join("\n\n");
+    }
+}
+
+/// Every paddle gets the controls of this machine, the session only reads them for the players
+/// here. Sides swap with every rematch, so the paddle of a player here changes.
+fn network_controller(args: &Args, bindings: &KeyBindings, side: Side) -> PaddleController {
+    // when every player is at this machine, as in a SyncTest session, each side gets its own keys
+    // and half of the screen
+    let controls = if args.synctest {
+        let mut controls = PlayerControls::new(bindings.scheme(side));
+        controls.zone = Some(side);
+        controls
+    } else {
+        PlayerControls::new(bindings.either())
+    };
+    PaddleController::Network {
+        local: Box::new(PaddleController::Player(controls)),
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn setup_online_player_controllers(
+    mut commands: Commands,
+    mut rip: ResMut<RollbackIdProvider>,
+    sides: Res<SideAssignment>,
+    (args, bindings): (Res<Args>, Res<KeyBindings>),
+    mut paddle_set: ParamSet<(
+        Query<(Entity, &mut Paddle), (Added<Paddle>, With<LeftPlayer>, Without<Rollback>)>,
+        Query<(Entity, &mut Paddle), (Added<Paddle>, With<RightPlayer>, Without<Rollback>)>,
+    )>,
+    // loading a snapshot re-inserts the rollback components, so only tag entities once
+    ball_query: Query<Entity, (Added<Ball>, Without<Rollback>)>,
+) {
+    if let Ok((left_paddle, mut paddle)) = paddle_set.p0().get_single_mut() {
+        paddle.handle = sides.handle(Side::Left);
+        commands.entity(left_paddle).insert((
+            Rollback::new(rip.next_id()),
+            network_controller(&args, &bindings, Side::Left),
+        ));
+    }
+
+    if let Ok((right_paddle, mut paddle)) = paddle_set.p1().get_single_mut() {
+        paddle.handle = sides.handle(Side::Right);
+        commands.entity(right_paddle).insert((
+            Rollback::new(rip.next_id()),
+            network_controller(&args, &bindings, Side::Right),
+        ));
+    }
+
+    if let Ok(ball) = ball_query.get_single() {
+        commands.entity(ball).insert(Rollback::new(rip.next_id()));
+    }
+}
+
+/// Number of frames simulated in the current session, rolled back with the rest of the match so a
+/// resimulated frame gets the same number again.
+#[derive(Resource, Debug, Default, Hash, Reflect)]
+#[reflect(Resource, Hash)]
+pub(crate) struct RollbackFrame(pub(crate) u32);
+
+/// Counts rollbacks by watching the rollback frame jump back, it is not rolled back itself
+#[derive(Resource, Debug, Default)]
+struct RollbackCounter {
+    last_frame: u32,
+    rollbacks: u32,
+}
+
+fn advance_rollback_frame(mut frame: ResMut<RollbackFrame>, mut counter: ResMut<RollbackCounter>) {
+    frame.0 += 1;
+    if frame.0 != counter.last_frame + 1 {
+        counter.rollbacks += 1;
+    }
+    counter.last_frame = frame.0;
+}
+
+const ROLLBACK_DEFAULT: &str = "rollback_default";
+
+/// GGRS setup shared by every session type, everything that makes up the match state is registered
+/// here so it is saved and restored on rollback.
+fn rollback_plugin() -> GGRSPlugin<GGRSConfig> {
+    GGRSPlugin::<GGRSConfig>::new()
+        .with_update_frequency(60)
+        .register_rollback_component::<Position>()
+        .register_rollback_component::<Ball>()
+        .register_rollback_component::<Paddle>()
+        .register_rollback_resource::<Score>()
+        .register_rollback_resource::<PongState>()
+        .register_rollback_resource::<MatchClock>()
+        .register_rollback_resource::<RallyLength>()
+        .register_rollback_resource::<BallSpeed>()
+        .register_rollback_resource::<RollbackFrame>()
+        .register_rollback_resource::<SideAssignment>()
+        .with_rollback_schedule(
+            Schedule::default().with_stage(
+                ROLLBACK_DEFAULT,
+                SystemStage::parallel()
+                    .with_system(advance_rollback_frame.before(GameloopStage::Input))
+                    // every paddle has a `Network` controller, so this reads the session inputs
+                    .with_system(controller_input.before(GameloopStage::Input))
+                    .with_system(
+                        rematch
+                            .run_in_state(GameType::Online)
+                            .run_in_state(GameState::Ingame)
+                            .before(GameloopStage::Input),
+                    )
+                    .with_system(
+                        serve::<LeftPlayer>
+                            .run_in_state(GameType::Online)
+                            .run_in_state(GameState::Ingame)
+                            .run_if_resource_equals(PongState::Serve(Side::Left))
+                            .label(GameloopStage::Input),
+                    )
+                    .with_system(
+                        serve::<RightPlayer>
+                            .run_in_state(GameType::Online)
+                            .run_in_state(GameState::Ingame)
+                            .run_if_resource_equals(PongState::Serve(Side::Right))
+                            .label(GameloopStage::Input),
+                    )
+                    .with_system(
+                        paddle_movement
+                            .run_in_state(GameType::Online)
+                            .run_in_state(GameState::Ingame)
+                            .after(GameloopStage::Input)
+                            .label(GameloopStage::Movement),
+                    )
+                    .with_system(
+                        ball_collision
+                            .run_in_state(GameType::Online)
+                            .run_in_state(GameState::Ingame)
+                            .run_if_resource_equals(PongState::Playing)
+                            .after(GameloopStage::Movement)
+                            .label(GameloopStage::Collision),
+                    )
+                    .with_system(
+                        ball_scoring
+                            .run_in_state(GameType::Online)
+                            .run_in_state(GameState::Ingame)
+                            .run_if_resource_equals(PongState::Playing)
+                            .after(GameloopStage::Collision)
+                            .label(GameloopStage::Scoring),
+                    )
+                    .with_system(
+                        update_match_state
+                            .run_in_state(GameType::Online)
+                            .run_in_state(GameState::Ingame)
+                            .after(GameloopStage::Scoring)
+                            .label(GameloopStage::MatchState),
+                    )
+                    .with_system(
+                        check_rollback_state
+                            .run_if_resource_exists::<ChecksumHistory>()
+                            .after(GameloopStage::MatchState),
+                    ),
+            ),
+        )
+}
+
+pub(crate) struct OnlinePlugin;
+
+impl Plugin for OnlinePlugin {
+    fn build(&self, app: &mut App) {
+        let fps = app.world.resource::<Args>().fps;
+        rollback_plugin()
+            .with_update_frequency(fps)
+            .with_input_system(input)
+            .build(app);
+
+        app.init_resource::<RollbackFrame>()
+            .init_resource::<RollbackCounter>()
+            .init_resource::<SideAssignment>()
+            .init_resource::<SideBannerShown>()
+            .init_resource::<RematchRequested>()
+            .init_resource::<LateSpectators>()
+            .add_enter_system(GameState::Lobby, setup_lobby)
+            .add_exit_system(GameState::Lobby, lobby_cleanup)
+            .add_enter_system(GameState::MainMenu, close_socket)
+            .add_system(handle_game_messages.run_if_resource_exists::<MatchSocket>())
+            .add_system(
+                announce_role
+                    .run_if_resource_exists::<MatchSocket>()
+                    .run_if_resource_exists::<PeerRoles>(),
+            )
+            .add_system_set(
+                ConditionSet::new()
+                    .run_in_state(GameState::Lobby)
+                    .with_system(leave_lobby)
+                    .with_system(type_room_code.run_unless_resource_exists::<LobbyRoom>())
+                    .with_system(pick_lobby_room.run_unless_resource_exists::<LobbyRoom>())
+                    .with_system(
+                        start_matchbox_socket
+                            .run_if_resource_exists::<LobbyRoom>()
+                            .run_unless_resource_exists::<MatchSocket>()
+                            .run_unless_resource_exists::<LobbyError>(),
+                    )
+                    .with_system(
+                        lobby
+                            .run_if_resource_exists::<MatchSocket>()
+                            .run_unless_resource_exists::<RelayedMatch>()
+                            .run_unless_resource_exists::<LatencyProbe>()
+                            .run_unless_resource_exists::<LobbyError>(),
+                    )
+                    .with_system(measure_latency.run_if_resource_exists::<LatencyProbe>())
+                    .with_system(show_lobby_error.run_if_resource_exists::<LobbyError>())
+                    .into(),
+            )
+            .add_system_set(
+                ConditionSet::new()
+                    .run_in_state(GameType::Online)
+                    .run_in_state(GameState::Ingame)
+                    .with_system(handle_ggrs_events.run_if_resource_exists::<Session<GGRSConfig>>())
+                    .with_system(tick_peer_connection.run_if_resource_exists::<PeerConnection>())
+                    .with_system(
+                        drop_pending_spectators
+                            .run_if_resource_exists::<PendingSpectators>()
+                            .run_if_resource_exists::<Session<GGRSConfig>>(),
+                    )
+                    .with_system(connection_overlay)
+                    .with_system(show_side_banner)
+                    .with_system(fade_side_banner)
+                    .with_system(leave_spectating)
+                    .with_system(
+                        snapshot_match
+                            .pipe(stream_match)
+                            .run_if_resource_exists::<Session<GGRSConfig>>()
+                            .run_if_resource_exists::<MatchSocket>(),
+                    )
+                    .with_system(follow_relayed_match.run_if_resource_exists::<RelayedMatch>())
+                    .with_system(toggle_network_stats)
+                    .with_system(
+                        poll_network_stats
+                            .run_if_resource_exists::<Session<GGRSConfig>>()
+                            .run_if_resource_exists::<NetworkStatsTimer>(),
+                    )
+                    .with_system(scoreboard)
+                    .with_system(post_match_screen)
+                    .with_system(post_match_buttons.run_if(match_over))
+                    .with_system(post_match_status.run_if_resource_exists::<Session<GGRSConfig>>())
+                    .into(),
+            )
+            .add_enter_system_set(
+                GameState::Ingame,
+                ConditionSet::new()
+                    .run_in_state(GameType::Online)
+                    .with_system(insert_timestep)
+                    .with_system(setup_network_stats)
+                    .with_system(show_spectator_banner)
+                    .into(),
+            )
+            .add_exit_system_set(
+                GameState::Ingame,
+                ConditionSet::new()
+                    .run_in_state(GameType::Online)
+                    .with_system(online_cleanup)
+                    .with_system(despawn_with::<ConnectionOverlay>)
+                    .with_system(despawn_with::<NetworkStatsText>)
+                    .with_system(despawn_with::<SideBanner>)
+                    .with_system(despawn_with::<SpectatorBanner>)
+                    .with_system(despawn_with::<PostMatchScreen>)
+                    .into(),
+            )
+            .add_system(
+                setup_online_player_controllers
+                    .run_in_state(GameType::Online)
+                    .run_in_state(GameState::Ingame),
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        controller::{PointerInput, Script},
+        local::LocalConfig,
+        pong::{setup_court, tests::simulation_app, MatchRules, PaddleInputs},
+    };
+
+    use super::*;
+
+    /// Pseudo random input for a player on a frame, the same every time it is asked for
+    fn scripted_input(frame: usize, handle: PlayerHandle) -> PaddleInput {
+        let mut x = (frame as u32)
+            .wrapping_mul(0x9e37_79b9)
+            .wrapping_add(handle as u32 + 1);
+        x ^= x >> 16;
+        x = x.wrapping_mul(0x85eb_ca6b);
+        x ^= x >> 13;
+        PaddleInput {
+            axis: (x >> 8) as i8,
+            serve: x & 1 != 0,
+        }
+    }
+
+    #[test]
+    fn room_codes_survive_sloppy_typing() {
+        let code = generate_room_code();
+        assert_eq!(parse_room_code(&code), Some(code.clone()));
+        assert_eq!(
+            parse_room_code(&format!(" {}-", code.to_lowercase())),
+            Some(code)
+        );
+        assert_eq!(parse_room_code("ABC"), None);
+        assert_eq!(parse_room_code("ABCD0"), None);
+        assert_eq!(private_room_id("pong", "K7XP3"), "pong-private-k7xp3");
+    }
+
+    #[test]
+    fn room_url_is_built_from_server_and_room() {
+        assert_eq!(
+            room_url("wss://pong-signalling-server.fly.dev/", "pong", true),
+            Ok("wss://pong-signalling-server.fly.dev/pong?next=2".to_string())
+        );
+        assert_eq!(
+            room_url("ws://10.0.0.5:3536", "office_42", true),
+            Ok("ws://10.0.0.5:3536/office_42?next=2".to_string())
+        );
+        assert_eq!(
+            room_url("ws://10.0.0.5:3536", "pong-private-k7xp3", false),
+            Ok("ws://10.0.0.5:3536/pong-private-k7xp3".to_string())
+        );
+        assert!(room_url("https://example.com", "pong", true).is_err());
+        assert!(room_url("wss://", "pong", true).is_err());
+        assert!(room_url("wss://example.com", "a room", true).is_err());
+    }
+
+    #[test]
+    fn every_peer_agrees_on_the_lineup() {
+        let peers = ["c", "a", "d", "b"].map(String::from).to_vec();
+        let mut reversed = peers.clone();
+        reversed.reverse();
+        assert_eq!(match_lineup(peers.clone(), 2), vec!["a", "b"]);
+        assert_eq!(match_lineup(reversed, 2), match_lineup(peers, 2));
+    }
+
+    #[test]
+    fn peers_need_the_same_fps_and_config() {
+        let config = config_hash(&PongConfig::default());
+        let hello = |role, fps, config| Hello { role, fps, config };
+        let ours = hello(Role::Player, 60, config);
+        assert_eq!(
+            incompatible_peer(&hello(Role::Spectator, 60, config), &ours),
+            None
+        );
+        assert!(incompatible_peer(&hello(Role::Player, 30, config), &ours).is_some());
+
+        let faster = config_hash(&PongConfig {
+            ball_speed: 25.,
+            ..default()
+        });
+        assert_ne!(faster, config);
+        assert!(incompatible_peer(&hello(Role::Player, 60, faster), &ours).is_some());
+    }
+
+    #[test]
+    fn sides_swap_between_rematches() {
+        let mut sides = SideAssignment::default();
+        assert_eq!(sides.handle(Side::Left), 0);
+        assert_eq!(sides.side(1), Some(Side::Right));
+        assert_eq!(sides.side(2), None);
+
+        sides.rematches += 1;
+        assert_eq!(sides.handle(Side::Left), 1);
+        assert_eq!(sides.side(0), Some(Side::Right));
+    }
+
+    #[test]
+    fn input_delay_covers_the_slowest_player() {
+        let ms = Duration::from_millis;
+        let round_trips = [
+            vec![ms(30), ms(500), ms(32)],
+            vec![ms(100), ms(90), ms(110)],
+        ];
+        assert_eq!(auto_input_delay(&round_trips[..1], 60), 1);
+        assert_eq!(auto_input_delay(&round_trips, 60), 3);
+        assert_eq!(auto_input_delay(&round_trips, 120), 6);
+        assert_eq!(auto_input_delay(&[vec![ms(2_000)]], 60), MAX_INPUT_DELAY);
+        assert_eq!(auto_input_delay(&[], 60), MAX_INPUT_DELAY);
+    }
+
+    #[test]
+    fn only_a_player_ahead_wins_when_the_opponent_leaves() {
+        let score = Score {
+            left: 3,
+            right: 5,
+            left_games: 1,
+            right_games: 0,
+        };
+        let playing = PongState::Playing;
+        let outcome = |score, side| match_outcome_without_opponent(score, &playing, Some(side));
+        assert_eq!(outcome(&score, Side::Left), "Opponent left\nYou win!");
+        assert_eq!(
+            outcome(&score, Side::Right),
+            "Opponent left\nMatch abandoned"
+        );
+        let tied = Score {
+            left: 2,
+            right: 2,
+            ..default()
+        };
+        assert_eq!(outcome(&tied, Side::Left), "Opponent left\nMatch abandoned");
+        assert_eq!(
+            match_outcome_without_opponent(
+                &score,
+                &PongState::GameOver(Side::Right),
+                Some(Side::Left)
+            ),
+            "Opponent left"
+        );
+    }
+
+    #[test]
+    fn late_spectators_see_the_match_they_are_streamed() {
+        let mut host = simulation_app();
+        for frame in 0..600 {
+            for handle in 0..2 {
+                host.world.resource_mut::<PaddleInputs>()[handle] = scripted_input(frame, handle);
+            }
+            host.update();
+        }
+        let mut snapshot_system = IntoSystem::into_system(snapshot_match);
+        snapshot_system.initialize(&mut host.world);
+        let snapshot = snapshot_system
+            .run((), &mut host.world)
+            .expect("the host should have a court");
+
+        let mut spectator = App::new();
+        spectator
+            .init_resource::<PongConfig>()
+            .init_resource::<Time>()
+            .init_resource::<Score>()
+            .init_resource::<PongState>()
+            .init_resource::<MatchClock>()
+            .insert_resource(RelayedMatch::new(snapshot))
+            .add_startup_system(setup_court)
+            .add_system(follow_relayed_match);
+        spectator.update();
+
+        let mut snapshot_system = IntoSystem::into_system(snapshot_match);
+        snapshot_system.initialize(&mut spectator.world);
+        assert_eq!(
+            snapshot_system.run((), &mut spectator.world),
+            Some(snapshot)
+        );
+    }
+
+    /// App running the rollback schedule in a SyncTest session that rolls back 7 frames every frame,
+    /// with both players following `scripted_input`
+    fn synctest_app(config: PongConfig) -> App {
+        let mut app = App::new();
+        app.add_loopless_state(GameState::Ingame)
+            .add_loopless_state(GameType::Online)
+            .insert_resource(config)
+            .insert_resource(Args {
+                synctest: true,
+                ..default()
+            })
+            .init_resource::<KeyBindings>()
+            .init_resource::<LocalConfig>()
+            .init_resource::<Input<KeyCode>>()
+            .init_resource::<Input<GamepadButton>>()
+            .init_resource::<Axis<GamepadAxis>>()
+            .init_resource::<Gamepads>()
+            .init_resource::<PointerInput>()
+            .init_resource::<RematchRequested>()
+            .init_resource::<Score>()
+            .init_resource::<PongState>()
+            .init_resource::<MatchClock>()
+            .init_resource::<RallyLength>()
+            .init_resource::<BallSpeed>()
+            .insert_resource(Timestep(Duration::from_millis(30)))
+            .init_resource::<RollbackFrame>()
+            .init_resource::<RollbackCounter>()
+            .init_resource::<SideAssignment>()
+            .insert_resource(ChecksumHistory::new(7))
+            .insert_resource(PaddleInputs(vec![PaddleInput::default(); 2]))
+            .add_startup_system(setup_court)
+            .add_system(setup_online_player_controllers);
+        rollback_plugin()
+            .with_update_frequency(1_000)
+            .with_input_system(input)
+            .build(&mut app);
+
+        // spawn the court and tag it for rollback before the session starts saving snapshots
+        app.update();
+        let mut controllers = app.world.query::<&mut PaddleController>();
+        for mut controller in controllers.iter_mut(&mut app.world) {
+            let script = PaddleController::Script(Script::new(scripted_input));
+            *controller = PaddleController::Network {
+                local: Box::new(script),
+            };
+        }
+
+        let session = SessionBuilder::<GGRSConfig>::new()
+            .with_num_players(2)
+            .with_check_distance(7)
+            .start_synctest_session()
+            .expect("Invalid synctest session");
+        app.insert_resource(Session::SyncTestSession(session));
+        app
+    }
+
+    #[test]
+    fn score_survives_rollbacks_over_goals() {
+        let mut app = synctest_app(PongConfig::default());
+        while app.world.resource::<RollbackFrame>().0 < 1_500 {
+            app.update();
+        }
+
+        // replay the same inputs without any rollback
+        let frames = app.world.resource::<RollbackFrame>().0 as usize;
+        let mut reference = simulation_app();
+        for frame in 0..frames {
+            for handle in 0..2 {
+                reference.world.resource_mut::<PaddleInputs>()[handle] =
+                    scripted_input(frame, handle);
+            }
+            reference.update();
+        }
+
+        let score = app.world.resource::<Score>();
+        let reference_score = reference.world.resource::<Score>();
+        assert!(
+            reference_score.left + reference_score.right > 0,
+            "the inputs should produce some goals"
+        );
+        assert_eq!(
+            (score.left, score.right),
+            (reference_score.left, reference_score.right)
+        );
+        assert_eq!(
+            app.world.resource::<PongState>(),
+            reference.world.resource::<PongState>()
+        );
+        assert_eq!(app.world.resource::<ChecksumHistory>().mismatches, 0);
+    }
+
+    #[test]
+    fn rematches_restart_in_lockstep() {
+        let config = PongConfig {
+            rules: MatchRules {
+                points_to_win: 1,
+                win_by_two: false,
+                ..default()
+            },
+            ..default()
+        };
+        let mut app = synctest_app(config);
+        // both players ask for a rematch all the time, so every match restarts the frame it ends
+        app.insert_resource(RematchRequested(true));
+
+        while app.world.resource::<SideAssignment>().rematches < 2
+            && app.world.resource::<RollbackFrame>().0 < 5_000
+        {
+            app.update();
+        }
+
+        let sides = app.world.resource::<SideAssignment>();
+        assert!(
+            sides.rematches >= 2,
+            "the inputs should finish some matches"
         );
+        let handle = sides.handle(Side::Left);
+        let mut left_query = app.world.query_filtered::<&Paddle, With<LeftPlayer>>();
+        assert_eq!(left_query.single(&app.world).handle, handle);
+        assert_eq!(app.world.resource::<ChecksumHistory>().mismatches, 0);
     }
 }
@@ -1,24 +1,48 @@
-use bevy::{
-    prelude::*,
-    sprite::collide_aabb::{collide, Collision},
-};
+use std::time::Duration;
+
+use bevy::prelude::*;
 use iyes_loopless::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::{types::GameState, util::despawn_with};
+use crate::{
+    collision::{sweep_inside, sweep_point, Aabb},
+    fixed::{Fixed, FixedVec2},
+    types::GameState,
+    util::despawn_with,
+};
 
-#[derive(Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone)]
 pub(crate) struct PaddleInput {
-    pub(crate) move_up: bool,
-    pub(crate) move_down: bool,
+    /// How fast the paddle moves up, or down when negative, at full speed at `AXIS_MAX`
+    pub(crate) axis: i8,
     pub(crate) serve: bool,
 }
 
+impl PaddleInput {
+    pub(crate) const AXIS_MAX: i8 = i8::MAX;
+
+    /// Axis of a stick or any other movement from -1 to 1, rounded to the nearest step
+    pub(crate) fn analog_axis(value: f32) -> i8 {
+        (value.clamp(-1., 1.) * Self::AXIS_MAX as f32).round() as i8
+    }
+
+    /// Axis of a pair of buttons, full speed when one of them is held
+    pub(crate) fn digital_axis(up: bool, down: bool) -> i8 {
+        match (up, down) {
+            (true, false) => Self::AXIS_MAX,
+            (false, true) => -Self::AXIS_MAX,
+            _ => 0,
+        }
+    }
+}
+
 #[derive(Component)]
 pub(crate) struct Court;
 
 pub trait CourtSide {}
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Reflect, FromReflect, Serialize, Deserialize)]
+#[reflect(Hash)]
 pub(crate) enum Side {
     Left,
     Right,
@@ -34,10 +58,23 @@ pub(crate) struct RightPlayer;
 
 impl CourtSide for RightPlayer {}
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+/// Phase of the rally, part of the rollback state so it is kept as a plain resource instead of a
+/// loopless state.
+#[derive(
+    Resource, Debug, Clone, Copy, Eq, PartialEq, Hash, Reflect, FromReflect, Serialize, Deserialize,
+)]
+#[reflect(Resource, Hash)]
 pub(crate) enum PongState {
     Serve(Side),
     Playing,
+    /// The match is over and won by a side
+    GameOver(Side),
+}
+
+impl Default for PongState {
+    fn default() -> Self {
+        PongState::Serve(Side::Left)
+    }
 }
 
 #[derive(SystemLabel)]
@@ -46,201 +83,565 @@ pub(crate) enum GameloopStage {
     Movement,
     Collision,
     Scoring,
+    MatchState,
 }
 
-#[derive(Debug, Component, Reflect, Default)]
+#[derive(Debug, Component, Reflect, Default, Hash)]
+#[reflect(Hash)]
 pub(crate) struct Paddle {
     pub(crate) handle: usize,
-    pub(crate) speed: f32,
-    pub(crate) direction: Vec2,
+    /// Top speed of the paddle
+    pub(crate) speed: Fixed,
+    /// Direction the paddle is moved in by its input
+    pub(crate) direction: FixedVec2,
+    /// Vertical distance the paddle actually moved in the last tick, kept until the next tick so
+    /// a ball hitting the paddle can pick up its movement
+    pub(crate) velocity: Fixed,
 }
 
-#[derive(Component, Reflect, Default)]
+#[derive(Debug, Component, Reflect, Default, Hash)]
+#[reflect(Hash)]
 pub(crate) struct Ball {
-    pub(crate) velocity: Vec3,
+    pub(crate) velocity: FixedVec2,
+}
+
+/// Position in the simulation, the `Transform` is derived from this for rendering only
+#[derive(Debug, Component, Reflect, Default, Clone, Copy, Hash, Deref, DerefMut)]
+#[reflect(Hash)]
+pub(crate) struct Position(pub(crate) FixedVec2);
+
+/// Size of the box an entity collides with
+#[derive(Debug, Component, Clone, Copy)]
+pub(crate) struct Collider {
+    pub(crate) size: FixedVec2,
+}
+
+/// How a match is won
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MatchRules {
+    /// Points needed to win a game
+    pub(crate) points_to_win: usize,
+    /// Only a lead of two points wins a game, it goes on past `points_to_win` until someone has it
+    pub(crate) win_by_two: bool,
+    /// Length of a game, when it runs out the player in the lead wins, a tied game goes to whoever
+    /// scores next
+    #[serde(deserialize_with = "crate::config::optional_seconds")]
+    pub(crate) time_limit: Option<Duration>,
+    /// Number of games in the match, the first player to win the majority of them wins the match
+    pub(crate) best_of: usize,
+}
+
+impl Default for MatchRules {
+    fn default() -> Self {
+        MatchRules {
+            points_to_win: 11,
+            win_by_two: true,
+            time_limit: None,
+            best_of: 1,
+        }
+    }
 }
 
-#[derive(Resource)]
+impl MatchRules {
+    /// Winner of the game with this score after `elapsed` time, if it is decided
+    pub(crate) fn game_winner(&self, score: &Score, elapsed: Duration) -> Option<Side> {
+        let (leader, lead, points) = match score.left.cmp(&score.right) {
+            std::cmp::Ordering::Greater => (Side::Left, score.left - score.right, score.left),
+            std::cmp::Ordering::Less => (Side::Right, score.right - score.left, score.right),
+            std::cmp::Ordering::Equal => return None,
+        };
+        let time_up = matches!(self.time_limit, Some(limit) if elapsed >= limit);
+        let enough_points = points >= self.points_to_win && (!self.win_by_two || lead >= 2);
+        (time_up || enough_points).then_some(leader)
+    }
+
+    /// Games a player needs to win the match
+    pub(crate) fn games_to_win(&self) -> usize {
+        self.best_of / 2 + 1
+    }
+}
+
+/// Tunables of the game, loaded from `assets/pong.config.ron`
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub(crate) struct PongConfig {
     pub(crate) court_size: [f32; 2],
+    /// Distance of the paddles from the center line, as a fraction of the court width
     pub(crate) players_distance_percentage: f32,
+    /// Top speed of the paddles
     pub(crate) paddle_speed: f32,
+    /// Paddles speed up and slow down gradually instead of moving at their top speed right away
+    pub(crate) paddle_momentum: Option<PaddleMomentum>,
+    /// Speed of the ball on the serve
     pub(crate) ball_speed: f32,
+    /// Factor the ball speed is multiplied with on every paddle hit
+    pub(crate) speed_up_per_hit: f32,
+    pub(crate) max_ball_speed: f32,
+    /// Steepest angle to the horizontal the ball leaves a paddle at, in degrees, reached when it
+    /// hits the very edge of the paddle
+    pub(crate) max_deflection_angle: f32,
+    /// Part of the max deflection angle a moving paddle adds in its direction of movement
+    pub(crate) english: f32,
+    pub(crate) paddle_size: [f32; 2],
+    pub(crate) ball_size: [f32; 2],
+    /// Distance between the dashes of the center line
+    pub(crate) dash_spacing: f32,
+    pub(crate) dash_size: [f32; 2],
+    pub(crate) rules: MatchRules,
+}
+
+impl Default for PongConfig {
+    fn default() -> Self {
+        PongConfig {
+            paddle_speed: 10.,
+            paddle_momentum: None,
+            ball_speed: 20.,
+            speed_up_per_hit: 1.05,
+            max_ball_speed: 40.,
+            max_deflection_angle: 60.,
+            english: 0.25,
+            court_size: [1600., 1000.],
+            players_distance_percentage: 0.4,
+            paddle_size: [17., 80.],
+            ball_size: [20., 20.],
+            dash_spacing: 30.,
+            dash_size: [5., 12.],
+            rules: MatchRules::default(),
+        }
+    }
+}
+
+/// How paddles gain and lose speed, in units per tick each tick
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PaddleMomentum {
+    /// Speed gained while the paddle is moved
+    pub(crate) acceleration: f32,
+    /// Speed lost while the paddle is left alone
+    pub(crate) friction: f32,
 }
 
-#[derive(Resource, Debug)]
+/// Points in the current game and games won in the match
+#[derive(Resource, Debug, Default, Hash, Reflect)]
+#[reflect(Resource, Hash)]
 pub(crate) struct Score {
     pub(crate) left: usize,
     pub(crate) right: usize,
+    pub(crate) left_games: usize,
+    pub(crate) right_games: usize,
 }
 
+/// Simulated time spent in the current game, counted in ticks of the simulation so it is the same
+/// on every peer and rolls back with the rest of the match
+#[derive(Resource, Debug, Default, Hash, Reflect)]
+#[reflect(Resource, Hash)]
+pub(crate) struct MatchClock {
+    pub(crate) elapsed: Duration,
+}
+
+/// Paddle hits since the last serve
+#[derive(Resource, Debug, Default, Hash, Reflect)]
+#[reflect(Resource, Hash)]
+pub(crate) struct RallyLength(pub(crate) u32);
+
+/// Speed of the ball in the current rally, set on the serve and raised by every paddle hit
+#[derive(Resource, Debug, Default, Hash, Reflect)]
+#[reflect(Resource, Hash)]
+pub(crate) struct BallSpeed(pub(crate) Fixed);
+
+/// Simulated time of a single tick of the game loop
+#[derive(Resource, Debug, Clone, Copy)]
+pub(crate) struct Timestep(pub(crate) Duration);
+
+#[derive(Component)]
+pub(crate) struct MatchInfoText;
+
 #[derive(Component)]
 pub(crate) struct Scoreboard;
 
 pub(crate) fn paddle_movement(
-    mut paddle_query: Query<(&mut Paddle, &mut Transform, &Sprite)>,
+    mut paddle_query: Query<(&mut Paddle, &mut Position, &Collider)>,
     inputs: Res<PaddleInputs>,
     config: Res<PongConfig>,
 ) {
-    let half_court_height = config.court_size[1] / 2.0;
+    let half_court_height = Fixed::from_f32(config.court_size[1]) / Fixed::from_int(2);
 
-    for (mut paddle, mut transform, sprite) in &mut paddle_query {
-        let input = inputs[paddle.handle as usize];
+    for (mut paddle, mut position, collider) in &mut paddle_query {
+        let input = inputs[paddle.handle];
 
-        if input.move_up && !input.move_down {
-            paddle.direction.y += 1.;
-        }
-        if !input.move_up && input.move_down {
-            paddle.direction.y -= 1.;
-        }
+        // an axis of i8::MIN from a remote peer still moves at full speed, not faster
+        let axis = input.axis.max(-PaddleInput::AXIS_MAX);
+        paddle.direction.y =
+            Fixed::from_int(axis.into()) / Fixed::from_int(PaddleInput::AXIS_MAX.into());
 
-        let paddle_half_height = sprite
-            .custom_size
-            .expect("Sprite should have custom height")
-            .y
-            / 2.0;
+        let velocity = match &config.paddle_momentum {
+            Some(momentum) => accelerate(&paddle, momentum),
+            None => paddle.direction.y * paddle.speed,
+        };
 
-        let translation = &mut transform.translation;
+        let paddle_half_height = collider.size.y / Fixed::from_int(2);
 
-        translation.y += paddle.direction.y * paddle.speed;
-        translation.y = translation.y.clamp(
+        let start = position.y;
+        position.y += velocity;
+        position.y = position.y.clamp(
             -half_court_height + paddle_half_height,
             half_court_height - paddle_half_height,
         );
 
-        paddle.direction = Vec2::new(0., 0.);
+        // a paddle running into the court wall stops dead
+        paddle.velocity = position.y - start;
     }
 }
 
-pub(crate) fn ball_movement(mut ball_query: Query<(&Ball, &mut Transform)>) {
-    let (ball, mut transform) = ball_query.single_mut();
-    transform.translation += ball.velocity;
+/// Velocity of a paddle after speeding up in the direction it is moved in, or slowing down by
+/// friction when it is left alone, up to its top speed
+fn accelerate(paddle: &Paddle, momentum: &PaddleMomentum) -> Fixed {
+    let direction = paddle.direction.y;
+    if direction == Fixed::ZERO {
+        let friction = Fixed::from_f32(momentum.friction);
+        return if paddle.velocity.abs() <= friction {
+            Fixed::ZERO
+        } else if paddle.velocity > Fixed::ZERO {
+            paddle.velocity - friction
+        } else {
+            paddle.velocity + friction
+        };
+    }
+    // speed up, or slow down, towards the speed the input asks for
+    let target = direction * paddle.speed;
+    let acceleration = Fixed::from_f32(momentum.acceleration);
+    if paddle.velocity < target {
+        (paddle.velocity + acceleration).min(target)
+    } else {
+        (paddle.velocity - acceleration).max(target)
+    }
 }
 
+/// Maximum number of bounces the ball resolves within a single tick, any movement left after the
+/// last bounce is dropped.
+const MAX_BOUNCES: usize = 4;
+
+/// Move the ball along its velocity for one tick, bouncing off the court walls and paddles.
+///
+/// Collisions are swept, so the ball can't tunnel through a paddle no matter how fast it goes, and
+/// it is left at the exact contact point when it reaches a goal line. Every paddle hit lengthens
+/// the rally and speeds the ball up, up to the maximum speed.
+#[allow(clippy::type_complexity)]
 pub(crate) fn ball_collision(
-    mut ball_query: Query<(&mut Ball, &mut Transform, &Sprite), (Without<Court>, Without<Paddle>)>,
-    court_collider_query: Query<(&Court, &Transform, &Sprite)>,
-    paddle_collider_query: Query<(&Paddle, &Transform, &Sprite)>,
+    mut ball_query: Query<(&mut Ball, &mut Position, &Collider), (Without<Court>, Without<Paddle>)>,
+    court_collider_query: Query<(&Position, &Collider), With<Court>>,
+    paddle_collider_query: Query<(&Paddle, &Position, &Collider)>,
+    config: Res<PongConfig>,
+    mut rally: ResMut<RallyLength>,
+    mut ball_speed: ResMut<BallSpeed>,
 ) {
-    let (mut ball, mut ball_transform, sprite) = ball_query.single_mut();
-    let ball_size = sprite.custom_size.expect("Ball should have custom size");
-    let velocity = &mut ball.velocity;
-    let (_court, court_transform, court_sprite) = court_collider_query.single();
-    let other_size = court_sprite
-        .custom_size
-        .expect("Collider should have custom size");
-
-    // Sometimes the ball clips through a wall, so we clamp the position to within the
-    // court bounds
-    let half_size = other_size / 2.0;
-    ball_transform.translation.x = ball_transform
-        .translation
-        .x
-        .clamp(-half_size.x, half_size.x);
-    ball_transform.translation.y = ball_transform
-        .translation
-        .y
-        .clamp(-half_size.y, half_size.y);
-
-    // check collision with court top and bottom
-    let collision = collide(
-        ball_transform.translation,
-        ball_size,
-        court_transform.translation,
-        other_size,
+    let (mut ball, mut ball_position, ball_collider) = ball_query.single_mut();
+    let (court_position, court_collider) = court_collider_query.single();
+    let court = Aabb::new(**court_position, court_collider.size);
+
+    let paddles: Vec<PaddleBody> = paddle_collider_query
+        .iter()
+        .map(|(paddle, paddle_position, paddle_collider)| PaddleBody {
+            bounds: Aabb::new(**paddle_position, paddle_collider.size),
+            movement: paddle.velocity / paddle.speed,
+        })
+        .collect();
+
+    let (position, mut velocity, paddle_hits) = sweep_ball(
+        **ball_position,
+        ball.velocity,
+        ball_collider.size,
+        &court,
+        &paddles,
+        &Deflection::new(&config),
     );
 
-    if let Some(collision) = collision {
-        match collision {
-            Collision::Top => velocity.y = -velocity.y.abs(),
-            Collision::Bottom => velocity.y = velocity.y.abs(),
-            _ => (),
+    if paddle_hits > 0 {
+        rally.0 += paddle_hits;
+        let max_speed = Fixed::from_f32(config.max_ball_speed);
+        for _ in 0..paddle_hits {
+            ball_speed.0 = (ball_speed.0 * Fixed::from_f32(config.speed_up_per_hit)).min(max_speed);
         }
+        velocity = velocity.normalize_or_zero() * ball_speed.0;
     }
 
-    // check collision with both paddles
-    for (_paddle, paddle_transform, paddle_sprite) in &paddle_collider_query {
-        let paddle_size = paddle_sprite
-            .custom_size
-            .expect("Paddle should have custom size");
-        let collision = collide(
-            ball_transform.translation,
-            ball_size,
-            paddle_transform.translation,
-            paddle_size,
-        );
-        if let Some(collision) = collision {
-            match collision {
-                Collision::Left | Collision::Right => {
-                    let paddle_ball_distance =
-                        paddle_transform.translation.y - ball_transform.translation.y;
-                    if paddle_ball_distance > (0.25 * paddle_size.y) {
-                        *velocity = Vec3::new(1., -1., 0.).normalize() * velocity.length();
-                    } else if paddle_ball_distance < -(0.25 * paddle_size.y) {
-                        *velocity = Vec3::new(1., 1., 0.).normalize() * velocity.length();
-                    } else {
-                        *velocity = Vec3::new(1., 0., 0.).normalize() * velocity.length();
-                    }
-                    match collision {
-                        Collision::Left => {
-                            velocity.x = -velocity.x.abs();
-                        }
-                        Collision::Right => {
-                            velocity.x = velocity.x.abs();
-                        }
-                        _ => (),
-                    };
-                }
-                Collision::Top => velocity.y = velocity.y.abs(),
-                Collision::Bottom => velocity.y = -velocity.y.abs(),
-                _ => (),
+    **ball_position = position;
+    ball.velocity = velocity;
+}
+
+/// A paddle as the ball sees it
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PaddleBody {
+    pub(crate) bounds: Aabb,
+    /// Speed the paddle moved at on this tick as a fraction of its top speed, positive up
+    pub(crate) movement: Fixed,
+}
+
+/// How the ball leaves a paddle it hits
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Deflection {
+    /// Steepest angle to the horizontal the ball leaves at, in radians
+    pub(crate) max_angle: Fixed,
+    /// Part of the max angle a moving paddle adds in its direction
+    pub(crate) english: Fixed,
+}
+
+impl Deflection {
+    pub(crate) fn new(config: &PongConfig) -> Self {
+        Deflection {
+            max_angle: Fixed::from_f32(config.max_deflection_angle) * Fixed::PI
+                / Fixed::from_int(180),
+            english: Fixed::from_f32(config.english),
+        }
+    }
+
+    /// Velocity of the ball leaving a paddle at `speed`, hit `offset` from its center as a fraction
+    /// of the distance to its edge, positive up. `outward` is the x direction away from the paddle.
+    ///
+    /// The angle grows linearly with the offset, from straight across in the center to the max
+    /// angle at the edges. It is worked out without signs, as fixed point multiplication rounds
+    /// down, so both paddles and both halves of a paddle send the ball off at mirrored angles.
+    pub(crate) fn velocity(
+        &self,
+        offset: Fixed,
+        movement: Fixed,
+        outward: Fixed,
+        speed: Fixed,
+    ) -> FixedVec2 {
+        let offset = offset.clamp(-Fixed::ONE, Fixed::ONE) + self.english * movement;
+        let angle = (offset.abs() * self.max_angle).min(self.max_angle);
+        let (sin, cos) = angle.sin_cos();
+        let up = if offset < Fixed::ZERO {
+            -Fixed::ONE
+        } else {
+            Fixed::ONE
+        };
+        FixedVec2::new(outward * (cos * speed), up * (sin * speed))
+    }
+}
+
+/// Advance a ball at `position` by `velocity` through the court, returning its new position and
+/// velocity, and how often it was hit by a paddle on the way.
+pub(crate) fn sweep_ball(
+    mut position: FixedVec2,
+    mut velocity: FixedVec2,
+    ball_size: FixedVec2,
+    court: &Aabb,
+    paddles: &[PaddleBody],
+    deflection: &Deflection,
+) -> (FixedVec2, FixedVec2, u32) {
+    let ball_half_size = ball_size / Fixed::from_int(2);
+    // The ball bounces off the top and bottom of the court, but is allowed to reach the left and
+    // right edges so it straddles the goal line for scoring.
+    let bounds = Aabb {
+        center: court.center,
+        half_extents: FixedVec2::new(
+            court.half_extents.x,
+            court.half_extents.y - ball_half_size.y,
+        ),
+    };
+    let colliders: Vec<Aabb> = paddles
+        .iter()
+        .map(|paddle| paddle.bounds.expand(ball_half_size))
+        .collect();
+    let mut paddle_hits = 0;
+
+    // A paddle may have moved onto the ball, push the ball out the closest side
+    for collider in &colliders {
+        if collider.contains(position) {
+            let to_max = collider.max() - position;
+            let to_min = position - collider.min();
+            let penetration = to_max.min(to_min);
+            if penetration.x < penetration.y {
+                let side = if to_max.x < to_min.x {
+                    Fixed::ONE
+                } else {
+                    -Fixed::ONE
+                };
+                position.x = collider.center.x + side * collider.half_extents.x;
+                velocity.x = side * velocity.x.abs();
+                paddle_hits += 1;
+            } else {
+                let side = if to_max.y < to_min.y {
+                    Fixed::ONE
+                } else {
+                    -Fixed::ONE
+                };
+                position.y = collider.center.y + side * collider.half_extents.y;
+                velocity.y = side * velocity.y.abs();
+            }
+        }
+    }
+    position.y = position.y.clamp(bounds.min().y, bounds.max().y);
+
+    let mut remaining = Fixed::ONE;
+    for _ in 0..MAX_BOUNCES {
+        let delta = velocity * remaining;
+
+        let wall_contact = sweep_inside(position, delta, &bounds)
+            .map(|contact| (contact, None, -contact.normal, &bounds));
+        let paddle_contact = colliders
+            .iter()
+            .zip(paddles)
+            .filter_map(|(collider, paddle)| {
+                sweep_point(position, delta, collider)
+                    .map(|contact| (contact, Some(paddle), contact.normal, collider))
+            })
+            .min_by_key(|(contact, ..)| contact.time);
+        let contact = match (wall_contact, paddle_contact) {
+            (Some(wall), Some(paddle)) if paddle.0.time <= wall.0.time => Some(paddle),
+            (Some(wall), _) => Some(wall),
+            (None, paddle) => paddle,
+        };
+
+        let Some((contact, paddle, face, surface)) = contact else {
+            position += delta;
+            break;
+        };
+
+        position += delta * contact.time;
+        // fixed point rounding can leave the ball just short of the surface, put it exactly on it
+        surface.snap_to_face(&mut position, face);
+        remaining *= Fixed::ONE - contact.time;
+
+        match paddle {
+            // reached the goal line, stop here and let scoring take over
+            None if !contact.normal.x.is_zero() => break,
+            None => velocity.y = contact.normal.y * velocity.y.abs(),
+            Some(paddle) if !contact.normal.x.is_zero() => {
+                paddle_hits += 1;
+                let offset = (position.y - surface.center.y) / surface.half_extents.y;
+                let outward = if contact.normal.x > Fixed::ZERO {
+                    Fixed::ONE
+                } else {
+                    -Fixed::ONE
+                };
+                velocity = deflection.velocity(offset, paddle.movement, outward, velocity.length());
             }
+            Some(_) => velocity.y = contact.normal.y * velocity.y.abs(),
         }
     }
+
+    (position, velocity, paddle_hits)
 }
 
 pub(crate) fn ball_scoring(
-    mut commands: Commands,
-    ball_query: Query<(&Ball, &Transform, &Sprite)>,
+    mut pong_state: ResMut<PongState>,
+    ball_query: Query<&Position, With<Ball>>,
     mut score: ResMut<Score>,
-    court_collider_query: Query<(&Court, &Transform, &Sprite)>,
+    court_collider_query: Query<(&Position, &Collider), With<Court>>,
 ) {
-    let (_ball, ball_transform, ball_sprite) = ball_query.single();
-    let ball_size = ball_sprite
-        .custom_size
-        .expect("Collider should have custom size");
-    let (_court, court_transform, court_sprite) = &court_collider_query.single();
-    let court_size = court_sprite
-        .custom_size
-        .expect("Court should have custom size");
-    let collision = collide(
-        ball_transform.translation,
-        ball_size,
-        court_transform.translation,
-        court_size,
-    );
+    let ball_position = ball_query.single();
+    let (court_position, court_collider) = court_collider_query.single();
+    let court = Aabb::new(**court_position, court_collider.size);
 
-    if let Some(collision) = collision {
-        match collision {
-            Collision::Left => {
-                score.right += 1;
-                commands.insert_resource(NextState(PongState::Serve(Side::Left)));
-            }
-            Collision::Right => {
-                score.left += 1;
-                commands.insert_resource(NextState(PongState::Serve(Side::Right)));
-            }
-            _ => (),
-        }
+    if ball_position.x <= court.min().x {
+        score.right += 1;
+        *pong_state = PongState::Serve(Side::Left);
+    } else if ball_position.x >= court.max().x {
+        score.left += 1;
+        *pong_state = PongState::Serve(Side::Right);
+    }
+}
+
+/// Advance the match clock and settle the game and the match by the rules, once the points of the
+/// tick are in. A finished game is followed by the next one, served by its loser, until a player
+/// won enough games to take the match.
+pub(crate) fn update_match_state(
+    config: Res<PongConfig>,
+    timestep: Res<Timestep>,
+    mut clock: ResMut<MatchClock>,
+    mut score: ResMut<Score>,
+    mut pong_state: ResMut<PongState>,
+) {
+    if matches!(*pong_state, PongState::GameOver(_)) {
+        return;
+    }
+    clock.elapsed += timestep.0;
+
+    let Some(winner) = config.rules.game_winner(&score, clock.elapsed) else {
+        return;
+    };
+    let (games, loser) = match winner {
+        Side::Left => (&mut score.left_games, Side::Right),
+        Side::Right => (&mut score.right_games, Side::Left),
+    };
+    *games += 1;
+    if *games >= config.rules.games_to_win() {
+        *pong_state = PongState::GameOver(winner);
+    } else {
+        score.left = 0;
+        score.right = 0;
+        clock.elapsed = Duration::ZERO;
+        *pong_state = PongState::Serve(loser);
+    }
+}
+
+/// Where a side's paddle starts a match
+pub(crate) fn paddle_start_position(config: &PongConfig, side: Side) -> Position {
+    let player_distance =
+        Fixed::from_f32(config.court_size[0]) * Fixed::from_f32(config.players_distance_percentage);
+    let x = match side {
+        Side::Left => -player_distance,
+        Side::Right => player_distance,
+    };
+    Position(FixedVec2::new(x, Fixed::ZERO))
+}
+
+/// Put the paddles and the ball back where a match starts
+pub(crate) fn reset_court(
+    config: &PongConfig,
+    paddle_query: &mut Query<(&mut Paddle, &mut Position, Option<&LeftPlayer>), Without<Ball>>,
+    ball_query: &mut Query<(&mut Ball, &mut Position), Without<Paddle>>,
+) {
+    for (mut paddle, mut position, left) in paddle_query {
+        let side = if left.is_some() {
+            Side::Left
+        } else {
+            Side::Right
+        };
+        *position = paddle_start_position(config, side);
+        paddle.direction = FixedVec2::ZERO;
+        paddle.velocity = Fixed::ZERO;
+    }
+    for (mut ball, mut position) in ball_query {
+        *position = Position(FixedVec2::ZERO);
+        ball.velocity = FixedVec2::ZERO;
     }
 }
 
+/// Copy simulation positions to the transforms used for rendering
+pub(crate) fn sync_transforms(mut query: Query<(&Position, &mut Transform), Changed<Position>>) {
+    for (position, mut transform) in &mut query {
+        let position = position.to_vec2();
+        transform.translation.x = position.x;
+        transform.translation.y = position.y;
+    }
+}
+
+/// Games won and the time left in the game, for the rules that have them
+fn match_info(rules: &MatchRules, score: &Score, clock: &MatchClock) -> String {
+    let mut info = Vec::new();
+    if rules.best_of > 1 {
+        info.push(format!("Games {}-{}", score.left_games, score.right_games));
+    }
+    if let Some(limit) = rules.time_limit {
+        let left = limit.saturating_sub(clock.elapsed).as_secs();
+        info.push(format!("{}:{:02}", left / 60, left % 60));
+    }
+    info.join("   ")
+}
+
+#[allow(clippy::type_complexity)]
 pub(crate) fn scoreboard(
     scoreboard: ResMut<Score>,
-
+    config: Res<PongConfig>,
+    clock: Res<MatchClock>,
     mut score_text_set: ParamSet<(
         Query<&mut Text, With<LeftPlayer>>,
         Query<&mut Text, With<RightPlayer>>,
+        Query<&mut Text, With<MatchInfoText>>,
     )>,
 ) {
     {
@@ -254,21 +655,34 @@ pub(crate) fn scoreboard(
         let mut right_score_text = right_query.single_mut();
         right_score_text.sections[0].value = format!("{}", scoreboard.right);
     }
+
+    for mut info_text in &mut score_text_set.p2() {
+        info_text.sections[0].value = match_info(&config.rules, &scoreboard, &clock);
+    }
 }
 
-pub(crate) fn adjust_scoreboard_scale(
+/// Keep the court in view when the window is resized or the config changes the court size
+fn fit_camera_to_court(
     config: Res<PongConfig>,
     windows: Res<Windows>,
     mut projections: Query<&mut OrthographicProjection, With<Camera>>,
-    mut scoreboard_query: Query<&mut Text, With<Scoreboard>>,
 ) {
-    let window = windows.primary();
-    let height_ratio = config.court_size[0] / window.height();
-
-    for mut ortho in projections.iter_mut() {
-        ortho.scale = height_ratio * 0.8;
+    let Some(window) = windows.get_primary() else {
+        return;
+    };
+    let scale = config.court_size[0] / window.height() * 0.8;
+    for mut ortho in &mut projections {
+        if ortho.scale != scale {
+            ortho.scale = scale;
+        }
     }
+}
 
+pub(crate) fn adjust_scoreboard_scale(
+    windows: Res<Windows>,
+    mut scoreboard_query: Query<&mut Text, With<Scoreboard>>,
+) {
+    let window = windows.primary();
     for mut score in &mut scoreboard_query {
         score.sections[0].style.font_size = window.height() * 0.07;
     }
@@ -278,64 +692,62 @@ pub(crate) fn adjust_scoreboard_scale(
 pub(crate) struct PaddleInputs(pub(crate) Vec<PaddleInput>);
 
 pub(crate) fn serve<T: CourtSide + Component>(
-    mut commands: Commands,
+    mut pong_state: ResMut<PongState>,
     config: Res<PongConfig>,
+    mut rally: ResMut<RallyLength>,
+    mut ball_speed: ResMut<BallSpeed>,
     inputs: Res<PaddleInputs>,
-    mut paddle_query: Query<(&Paddle, &mut Transform), With<T>>,
-    mut ball_query: Query<(&mut Ball, &mut Transform), Without<Paddle>>,
+    paddle_query: Query<(&Paddle, &Position), With<T>>,
+    mut ball_query: Query<(&mut Ball, &mut Position), Without<Paddle>>,
 ) {
     if paddle_query.is_empty() {
         return;
     }
 
-    let (paddle, paddle_transform) = paddle_query.single_mut();
-    let (mut ball, mut ball_transform) = ball_query.single_mut();
+    let (paddle, paddle_position) = paddle_query.single();
+    let (mut ball, mut ball_position) = ball_query.single_mut();
 
-    ball_transform.translation.x = paddle_transform.translation.x * 0.8;
-    ball_transform.translation.y = paddle_transform.translation.y;
+    ball_position.x = paddle_position.x * Fixed::from_f32(0.8);
+    ball_position.y = paddle_position.y;
 
     let bounce_direction = {
-        if paddle_transform.translation.x.is_sign_positive() {
-            -1.
+        if paddle_position.x >= Fixed::ZERO {
+            -1
         } else {
-            1.
+            1
         }
     };
 
-    let input = inputs[paddle.handle as usize];
+    let input = inputs[paddle.handle];
     if input.serve {
-        commands.insert_resource(NextState(PongState::Playing));
-        if input.move_up && !input.move_down {
-            ball.velocity = config.ball_speed * Vec3::new(bounce_direction, 1., 0.).normalize();
-        } else if !input.move_up && input.move_down {
-            ball.velocity = config.ball_speed * Vec3::new(bounce_direction, -1., 0.).normalize();
-        } else {
-            ball.velocity = config.ball_speed * Vec3::new(bounce_direction, 0., 0.).normalize();
-        }
+        *pong_state = PongState::Playing;
+        rally.0 = 0;
+        ball_speed.0 = Fixed::from_f32(config.ball_speed);
+        let ball_speed = ball_speed.0;
+        let serve_direction = FixedVec2::from_ints(bounce_direction, input.axis.signum().into());
+        ball.velocity = ball_speed * serve_direction.normalize_or_zero();
     }
 }
 
-pub(crate) fn setup_camera(mut commands: Commands, config: Res<PongConfig>, windows: Res<Windows>) {
-    let window = windows.primary();
-
-    let height_ratio = window.height() / config.court_size[0];
-    commands.spawn(Camera2dBundle {
-        projection: OrthographicProjection {
-            scale: height_ratio,
-            ..default()
-        },
-        ..default()
-    });
+/// Spawn the camera, `fit_camera_to_court` scales it to the court from then on
+pub(crate) fn setup_camera(mut commands: Commands) {
+    commands.spawn(Camera2dBundle::default());
 }
 
-#[derive(SystemLabel)]
-pub(crate) struct PongGameSetup;
-
 pub(crate) fn setup_court(mut commands: Commands, config: Res<PongConfig>) {
-    let paddle_speed = config.paddle_speed;
+    spawn_court(&mut commands, &config);
+}
+
+/// Spawn the court with the paddles and the ball in their starting positions
+pub(crate) fn spawn_court(commands: &mut Commands, config: &PongConfig) {
+    let paddle_speed = Fixed::from_f32(config.paddle_speed);
     commands
         .spawn((
             Court,
+            Position(FixedVec2::ZERO),
+            Collider {
+                size: FixedVec2::from_vec2(Vec2::from_array(config.court_size)),
+            },
             SpriteBundle {
                 sprite: Sprite {
                     color: Color::BLACK,
@@ -346,34 +758,39 @@ pub(crate) fn setup_court(mut commands: Commands, config: Res<PongConfig>) {
             },
         ))
         .with_children(|parent| {
-            let num_dashes = (config.court_size[1] / 30.0) as i32;
+            let num_dashes = (config.court_size[1] / config.dash_spacing) as i32;
             for y in 0..num_dashes {
                 parent.spawn(SpriteBundle {
                     transform: Transform::from_translation(Vec3::new(
                         0.0,
-                        y as f32 * 30.0 - config.court_size[1] / 2.0 + 20.0,
+                        (y as f32 - (num_dashes - 1) as f32 / 2.0) * config.dash_spacing,
                         1.0,
                     )),
                     sprite: Sprite {
                         color: Color::WHITE,
-                        custom_size: Some(Vec2::new(5.0, 12.0)),
+                        custom_size: Some(Vec2::from_array(config.dash_size)),
                         ..default()
                     },
                     ..default()
                 });
             }
 
-            let paddle_size = Vec2::new(17.0, 80.0);
-            let player_distance = config.court_size[0] * config.players_distance_percentage;
+            let paddle_size = Vec2::from_array(config.paddle_size);
+            let left_position = paddle_start_position(config, Side::Left);
             parent.spawn((
                 LeftPlayer,
                 Paddle {
                     handle: 0,
                     speed: paddle_speed,
-                    direction: Vec2::new(0., 0.),
+                    direction: FixedVec2::ZERO,
+                    velocity: Fixed::ZERO,
+                },
+                left_position,
+                Collider {
+                    size: FixedVec2::from_vec2(paddle_size),
                 },
                 SpriteBundle {
-                    transform: Transform::from_translation(Vec3::new(-player_distance, 0.0, 1.0)),
+                    transform: Transform::from_translation(left_position.to_vec2().extend(1.0)),
                     sprite: Sprite {
                         color: Color::WHITE,
                         custom_size: Some(paddle_size),
@@ -383,15 +800,21 @@ pub(crate) fn setup_court(mut commands: Commands, config: Res<PongConfig>) {
                 },
             ));
 
+            let right_position = paddle_start_position(config, Side::Right);
             parent.spawn((
                 RightPlayer,
                 Paddle {
                     handle: 1,
                     speed: paddle_speed,
-                    direction: Vec2::new(0., 0.),
+                    direction: FixedVec2::ZERO,
+                    velocity: Fixed::ZERO,
+                },
+                right_position,
+                Collider {
+                    size: FixedVec2::from_vec2(paddle_size),
                 },
                 SpriteBundle {
-                    transform: Transform::from_translation(Vec3::new(player_distance, 0.0, 1.0)),
+                    transform: Transform::from_translation(right_position.to_vec2().extend(1.0)),
                     sprite: Sprite {
                         color: Color::rgb(1.0, 1.0, 1.0),
                         custom_size: Some(paddle_size),
@@ -401,15 +824,20 @@ pub(crate) fn setup_court(mut commands: Commands, config: Res<PongConfig>) {
                 },
             ));
 
+            let ball_size = Vec2::from_array(config.ball_size);
             parent.spawn((
                 Ball {
-                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: FixedVec2::ZERO,
+                },
+                Position(FixedVec2::ZERO),
+                Collider {
+                    size: FixedVec2::from_vec2(ball_size),
                 },
                 SpriteBundle {
                     transform: Transform::from_translation(Vec3::new(0.0, 0.0, 1.0)),
                     sprite: Sprite {
                         color: Color::WHITE,
-                        custom_size: Some(Vec2::new(20.0, 20.0)),
+                        custom_size: Some(ball_size),
                         ..default()
                     },
                     ..default()
@@ -426,11 +854,44 @@ pub(crate) fn setup_scoreboard(
     let window = windows.primary();
     let font = asset_server.load("fonts/PublicPixel-z84yD.ttf");
     let text_style = TextStyle {
-        font,
+        font: font.clone(),
         font_size: window.height() * 0.15,
         ..default()
     };
 
+    commands
+        .spawn((
+            Scoreboard,
+            NodeBundle {
+                style: Style {
+                    size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                    position_type: PositionType::Absolute,
+                    align_items: AlignItems::FlexStart,
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                background_color: Color::NONE.into(),
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                MatchInfoText,
+                TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font,
+                        font_size: 20.,
+                        color: Color::GRAY,
+                    },
+                )
+                .with_style(Style {
+                    margin: UiRect::bottom(Val::Px(20.)),
+                    ..default()
+                }),
+            ));
+        });
+
     commands
         .spawn((
             Scoreboard,
@@ -474,26 +935,515 @@ pub(crate) fn setup_scoreboard(
         });
 }
 
+/// Start the next match from a clean score
+pub(crate) fn reset_match_state(commands: &mut Commands) {
+    commands.insert_resource(Score::default());
+    commands.insert_resource(PongState::default());
+    commands.insert_resource(MatchClock::default());
+    commands.insert_resource(RallyLength::default());
+    commands.insert_resource(BallSpeed::default());
+}
+
+fn reset_match(mut commands: Commands) {
+    reset_match_state(&mut commands);
+}
+
 pub(crate) struct PongPlugin;
 
 impl Plugin for PongPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(Score { left: 0, right: 0 })
-            .insert_resource(PongConfig {
-                paddle_speed: 10.,
-                ball_speed: 20.,
-                court_size: [1600., 1000.],
-                players_distance_percentage: 0.4,
-            })
+        app.init_resource::<Score>()
+            .init_resource::<PongConfig>()
             .insert_resource(PaddleInputs(vec![
                 PaddleInput { ..default() },
                 PaddleInput { ..default() },
             ]))
-            .add_loopless_state(PongState::Serve(Side::Left))
+            .init_resource::<PongState>()
+            .init_resource::<MatchClock>()
+            .init_resource::<RallyLength>()
+            .init_resource::<BallSpeed>()
             .add_enter_system(GameState::Ingame, setup_court)
             .add_enter_system(GameState::Ingame, setup_scoreboard)
             .add_exit_system(GameState::Ingame, despawn_with::<Court>)
             .add_exit_system(GameState::Ingame, despawn_with::<Scoreboard>)
-            .add_system(adjust_scoreboard_scale.run_in_state(GameState::Ingame));
+            .add_exit_system(GameState::Ingame, reset_match)
+            .add_system(fit_camera_to_court)
+            .add_system(adjust_scoreboard_scale.run_in_state(GameState::Ingame))
+            .add_system(sync_transforms.run_in_state(GameState::Ingame));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        collections::hash_map::DefaultHasher,
+        hash::{Hash, Hasher},
+    };
+
+    use super::*;
+
+    fn fixed(value: f32) -> Fixed {
+        Fixed::from_f32(value)
+    }
+
+    fn vec(x: f32, y: f32) -> FixedVec2 {
+        FixedVec2::from_vec2(Vec2::new(x, y))
+    }
+
+    fn ball_size() -> FixedVec2 {
+        vec(20.0, 20.0)
+    }
+
+    fn court() -> Aabb {
+        Aabb::new(FixedVec2::ZERO, vec(1600.0, 1000.0))
+    }
+
+    fn paddles() -> [PaddleBody; 2] {
+        [-640.0, 640.0].map(|x| PaddleBody {
+            bounds: Aabb::new(vec(x, 0.0), vec(17.0, 80.0)),
+            movement: Fixed::ZERO,
+        })
+    }
+
+    fn deflection() -> Deflection {
+        Deflection::new(&PongConfig::default())
+    }
+
+    /// Largest x the ball center can reach in front of the right paddle
+    fn right_paddle_face() -> Fixed {
+        fixed(640.0 - (17.0 + 20.0) / 2.0)
+    }
+
+    #[test]
+    fn ball_never_passes_through_paddle() {
+        let paddles = paddles();
+        for speed in [20.0, 100.0, 500.0, 1_000.0, 5_000.0, 100_000.0] {
+            for start_y in [-35.0, -10.0, 0.0, 10.0, 35.0] {
+                let (position, ..) = sweep_ball(
+                    vec(610.0, start_y),
+                    vec(speed, 0.0),
+                    ball_size(),
+                    &court(),
+                    &paddles,
+                    &deflection(),
+                );
+                assert!(
+                    position.x <= right_paddle_face(),
+                    "ball at speed {speed} passed through the paddle to {position:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ball_stays_in_front_of_paddles_over_many_ticks() {
+        let paddles = paddles();
+        let mut position = FixedVec2::ZERO;
+        let mut velocity = vec(3_000.0, 0.0);
+        for _ in 0..1_000 {
+            (position, velocity, _) = sweep_ball(
+                position,
+                velocity,
+                ball_size(),
+                &court(),
+                &paddles,
+                &deflection(),
+            );
+            assert!(position.x.abs() <= right_paddle_face(), "{position:?}");
+        }
+    }
+
+    #[test]
+    fn ball_resolves_to_contact_point() {
+        let (position, velocity, _) = sweep_ball(
+            vec(610.0, 0.0),
+            vec(20.0, 0.0),
+            ball_size(),
+            &court(),
+            &paddles(),
+            &deflection(),
+        );
+        // 11.5 px to the paddle face and 8.5 px back out
+        assert!((position.x - (right_paddle_face() - fixed(8.5))).abs() < fixed(0.01));
+        assert_eq!(velocity, vec(-20.0, 0.0));
+    }
+
+    #[test]
+    fn ball_bounces_off_walls_multiple_times_per_tick() {
+        let (position, velocity, _) = sweep_ball(
+            FixedVec2::ZERO,
+            vec(0.0, 2_500.0),
+            ball_size(),
+            &court(),
+            &[],
+            &deflection(),
+        );
+        // 490 up, 980 down, 980 up, 50 down
+        assert!(
+            (position.y - fixed(440.0)).abs() < fixed(0.1),
+            "{position:?}"
+        );
+        assert!(velocity.y < Fixed::ZERO);
+    }
+
+    #[test]
+    fn ball_stops_on_goal_line() {
+        let (position, ..) = sweep_ball(
+            vec(0.0, 300.0),
+            vec(-5_000.0, 0.0),
+            ball_size(),
+            &court(),
+            &paddles(),
+            &deflection(),
+        );
+        assert_eq!(position, vec(-800.0, 300.0));
+    }
+
+    #[test]
+    fn paddle_moving_onto_ball_pushes_it_out() {
+        let (position, velocity, _) = sweep_ball(
+            vec(640.0, 45.0),
+            vec(20.0, -10.0),
+            ball_size(),
+            &court(),
+            &paddles(),
+            &deflection(),
+        );
+        assert!(position.y >= fixed(50.0), "{position:?}");
+        assert!(velocity.y > Fixed::ZERO);
+    }
+
+    /// Velocity of a ball sent straight at the center of a paddle, hitting it `offset` above its
+    /// center with the paddle moving in `movement`
+    fn deflect(side: Side, offset: f32, movement: Fixed) -> FixedVec2 {
+        let mut paddles = paddles();
+        let (index, x, speed) = match side {
+            Side::Left => (0, -610.0, -20.0),
+            Side::Right => (1, 610.0, 20.0),
+        };
+        paddles[index].movement = movement;
+        let (_, velocity, hits) = sweep_ball(
+            vec(x, offset),
+            vec(speed, 0.0),
+            ball_size(),
+            &court(),
+            &paddles,
+            &deflection(),
+        );
+        assert_eq!(hits, 1);
+        velocity
+    }
+
+    #[test]
+    fn deflection_is_symmetric() {
+        let mirrored = |velocity: FixedVec2| FixedVec2::new(-velocity.x, velocity.y);
+        let flipped = |velocity: FixedVec2| FixedVec2::new(velocity.x, -velocity.y);
+        for offset in [0.0, 5.0, 20.0, 49.0] {
+            for movement in [-Fixed::ONE, Fixed::ZERO, Fixed::ONE] {
+                let left = deflect(Side::Left, offset, movement);
+                let right = deflect(Side::Right, offset, movement);
+                assert_eq!(
+                    left,
+                    mirrored(right),
+                    "offset {offset}, movement {movement:?}"
+                );
+                assert_eq!(
+                    deflect(Side::Left, -offset, -movement),
+                    flipped(left),
+                    "offset {offset}, movement {movement:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn deflection_grows_with_the_offset_up_to_the_max_angle() {
+        let angle = |velocity: FixedVec2| velocity.y.to_f32().atan2(velocity.x.to_f32());
+        let mut last = 0.0;
+        for offset in [0.0, 10.0, 20.0, 30.0, 40.0, 49.0] {
+            let velocity = deflect(Side::Left, offset, Fixed::ZERO);
+            assert!(angle(velocity) >= last, "offset {offset}");
+            assert!((velocity.length() - fixed(20.0)).abs() < fixed(0.01));
+            last = angle(velocity);
+        }
+        assert!((last - 60f32.to_radians()).abs() < 0.03, "{last}");
+
+        // english steepens the angle in the direction of movement, but never past the max
+        let still = angle(deflect(Side::Left, 10.0, Fixed::ZERO));
+        assert!(angle(deflect(Side::Left, 10.0, Fixed::ONE)) > still);
+        assert!(angle(deflect(Side::Left, 10.0, -Fixed::ONE)) < still);
+        let edge = angle(deflect(Side::Left, 45.0, Fixed::ONE));
+        assert!((edge - 60f32.to_radians()).abs() < 0.01, "{edge}");
+    }
+
+    /// App running the simulation systems on whatever is in `PaddleInputs`, without rendering
+    pub(crate) fn simulation_app() -> App {
+        let mut app = App::new();
+        app.init_resource::<PongConfig>()
+            .init_resource::<Score>()
+            .init_resource::<PongState>()
+            .init_resource::<MatchClock>()
+            .init_resource::<RallyLength>()
+            .init_resource::<BallSpeed>()
+            .insert_resource(Timestep(Duration::from_millis(30)))
+            .insert_resource(PaddleInputs(vec![PaddleInput::default(); 2]))
+            .add_startup_system(setup_court)
+            .add_system(
+                serve::<LeftPlayer>
+                    .run_if_resource_equals(PongState::Serve(Side::Left))
+                    .label(GameloopStage::Input),
+            )
+            .add_system(
+                serve::<RightPlayer>
+                    .run_if_resource_equals(PongState::Serve(Side::Right))
+                    .label(GameloopStage::Input),
+            )
+            .add_system(
+                paddle_movement
+                    .after(GameloopStage::Input)
+                    .label(GameloopStage::Movement),
+            )
+            .add_system(
+                ball_collision
+                    .run_if_resource_equals(PongState::Playing)
+                    .after(GameloopStage::Movement)
+                    .label(GameloopStage::Collision),
+            )
+            .add_system(
+                ball_scoring
+                    .run_if_resource_equals(PongState::Playing)
+                    .after(GameloopStage::Collision)
+                    .label(GameloopStage::Scoring),
+            )
+            .add_system(
+                update_match_state
+                    .after(GameloopStage::Scoring)
+                    .label(GameloopStage::MatchState),
+            );
+        app
+    }
+
+    #[test]
+    fn paddle_hits_speed_up_the_ball() {
+        let mut app = simulation_app();
+        app.update();
+
+        let hit_right_paddle = |app: &mut App, speed: f32| {
+            app.insert_resource(PongState::Playing);
+            app.insert_resource(BallSpeed(fixed(speed)));
+            let mut balls = app.world.query::<(&mut Ball, &mut Position)>();
+            let (mut ball, mut position) = balls.single_mut(&mut app.world);
+            **position = vec(610.0, 0.0);
+            ball.velocity = vec(speed, 0.0);
+            app.update();
+            app.world.resource::<BallSpeed>().0
+        };
+
+        let speed = hit_right_paddle(&mut app, 20.0);
+        assert_eq!(speed, fixed(20.0) * fixed(1.05));
+        assert_eq!(app.world.resource::<RallyLength>().0, 1);
+        let mut balls = app.world.query::<&Ball>();
+        let velocity = balls.single(&app.world).velocity;
+        assert!(velocity.x < Fixed::ZERO);
+        assert!((velocity.length() - speed).abs() < fixed(0.01));
+
+        let speed = hit_right_paddle(&mut app, 39.0);
+        assert_eq!(speed, fixed(40.0));
+        assert_eq!(app.world.resource::<RallyLength>().0, 2);
+
+        // the next serve starts over
+        app.insert_resource(PongState::Serve(Side::Left));
+        app.world.resource_mut::<PaddleInputs>()[0].serve = true;
+        app.update();
+        assert_eq!(app.world.resource::<BallSpeed>().0, fixed(20.0));
+        assert_eq!(app.world.resource::<RallyLength>().0, 0);
+    }
+
+    #[test]
+    fn paddles_move_as_fast_as_their_axis_asks() {
+        let mut app = simulation_app();
+        app.update();
+
+        let tick = |app: &mut App, axis: i8| {
+            app.world.resource_mut::<PaddleInputs>()[0].axis = axis;
+            app.update();
+            let mut paddles = app.world.query::<(&Paddle, With<LeftPlayer>)>();
+            paddles.single(&app.world).0.velocity
+        };
+        assert_eq!(tick(&mut app, PaddleInput::AXIS_MAX), fixed(10.));
+        assert_eq!(tick(&mut app, i8::MIN), fixed(-10.));
+        let half_speed = tick(&mut app, PaddleInput::analog_axis(-0.5));
+        assert!(
+            (half_speed - fixed(-5.)).abs() < fixed(0.1),
+            "{half_speed:?}"
+        );
+        assert_eq!(PaddleInput::analog_axis(0.25), 32);
+        assert_eq!(PaddleInput::analog_axis(3.), PaddleInput::AXIS_MAX);
+    }
+
+    #[test]
+    fn paddles_with_momentum_speed_up_and_slide_to_a_stop() {
+        let mut app = simulation_app();
+        app.insert_resource(PongConfig {
+            paddle_momentum: Some(PaddleMomentum {
+                acceleration: 4.,
+                friction: 3.,
+            }),
+            ..default()
+        });
+        app.update();
+
+        let tick = |app: &mut App, move_up: bool| {
+            app.world.resource_mut::<PaddleInputs>()[0].axis =
+                PaddleInput::digital_axis(move_up, false);
+            app.update();
+            let mut paddles = app.world.query::<(&Paddle, &Position, With<LeftPlayer>)>();
+            let (paddle, position, _) = paddles.single(&app.world);
+            (paddle.velocity, position.y)
+        };
+
+        let velocities: Vec<Fixed> = (0..4).map(|_| tick(&mut app, true).0).collect();
+        assert_eq!(velocities, [fixed(4.), fixed(8.), fixed(10.), fixed(10.)]);
+        let (_, moved_to) = tick(&mut app, true);
+        assert_eq!(moved_to, fixed(42.));
+
+        let velocities: Vec<Fixed> = (0..5).map(|_| tick(&mut app, false).0).collect();
+        assert_eq!(
+            velocities,
+            [fixed(7.), fixed(4.), fixed(1.), Fixed::ZERO, Fixed::ZERO]
+        );
+        let (_, stopped_at) = tick(&mut app, false);
+        assert_eq!(stopped_at, fixed(54.));
+    }
+
+    /// Hash of everything the simulation depends on
+    fn state_hash(app: &mut App) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        let world = &mut app.world;
+        world.resource::<Score>().hash(&mut hasher);
+        world.resource::<PongState>().hash(&mut hasher);
+        world.resource::<MatchClock>().hash(&mut hasher);
+        world.resource::<RallyLength>().hash(&mut hasher);
+        world.resource::<BallSpeed>().hash(&mut hasher);
+        let mut paddles = world.query::<(&Paddle, &Position)>();
+        let mut paddles: Vec<_> = paddles.iter(world).collect();
+        paddles.sort_by_key(|(paddle, _)| paddle.handle);
+        paddles.hash(&mut hasher);
+        let mut balls = world.query::<(&Ball, &Position)>();
+        for ball in balls.iter(world) {
+            ball.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Run the simulation on a pseudo random input stream and return the state hash of every tick
+    fn run_simulation(ticks: usize) -> (Vec<u64>, usize) {
+        let mut app = simulation_app();
+        let mut seed: u32 = 0x2545_f491;
+        let mut hashes = Vec::with_capacity(ticks);
+        for _ in 0..ticks {
+            for input in app.world.resource_mut::<PaddleInputs>().iter_mut() {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                *input = PaddleInput {
+                    axis: (seed >> 8) as i8,
+                    serve: seed & 0b100 != 0,
+                };
+            }
+            app.update();
+            hashes.push(state_hash(&mut app));
+        }
+        let score = app.world.resource::<Score>();
+        (hashes, score.left + score.right)
+    }
+
+    #[test]
+    fn simulation_is_deterministic() {
+        let (first, points) = run_simulation(5_000);
+        let (second, _) = run_simulation(5_000);
+        assert!(points > 0, "the input stream should produce some goals");
+        assert_eq!(first, second);
+    }
+
+    fn points(left: usize, right: usize) -> Score {
+        Score {
+            left,
+            right,
+            ..default()
+        }
+    }
+
+    #[test]
+    fn games_are_won_by_the_rules() {
+        let rules = MatchRules::default();
+        let no_time = Duration::ZERO;
+        assert_eq!(rules.game_winner(&points(11, 9), no_time), Some(Side::Left));
+        assert_eq!(rules.game_winner(&points(11, 10), no_time), None);
+        assert_eq!(
+            rules.game_winner(&points(12, 14), no_time),
+            Some(Side::Right)
+        );
+
+        let sudden = MatchRules {
+            win_by_two: false,
+            ..default()
+        };
+        assert_eq!(
+            sudden.game_winner(&points(11, 10), no_time),
+            Some(Side::Left)
+        );
+
+        let timed = MatchRules {
+            time_limit: Some(Duration::from_secs(60)),
+            ..default()
+        };
+        let time_up = Duration::from_secs(60);
+        assert_eq!(timed.game_winner(&points(3, 2), no_time), None);
+        assert_eq!(timed.game_winner(&points(3, 2), time_up), Some(Side::Left));
+        // a tied game goes on until the next point
+        assert_eq!(timed.game_winner(&points(2, 2), time_up), None);
+
+        assert_eq!(rules.games_to_win(), 1);
+        let best_of_five = MatchRules {
+            best_of: 5,
+            ..default()
+        };
+        assert_eq!(best_of_five.games_to_win(), 3);
+    }
+
+    #[test]
+    fn match_goes_on_until_enough_games_are_won() {
+        let mut app = App::new();
+        app.insert_resource(PongConfig {
+            rules: MatchRules {
+                points_to_win: 3,
+                best_of: 3,
+                ..default()
+            },
+            ..default()
+        })
+        .init_resource::<MatchClock>()
+        .insert_resource(Timestep(Duration::from_millis(30)))
+        .insert_resource(points(3, 1))
+        .insert_resource(PongState::Serve(Side::Right))
+        .add_system(update_match_state);
+
+        app.update();
+        let score = app.world.resource::<Score>();
+        assert_eq!((score.left, score.right, score.left_games), (0, 0, 1));
+        assert_eq!(
+            *app.world.resource::<PongState>(),
+            PongState::Serve(Side::Right)
+        );
+        assert_eq!(app.world.resource::<MatchClock>().elapsed, Duration::ZERO);
+
+        app.world.resource_mut::<Score>().left = 3;
+        app.update();
+        assert_eq!(
+            *app.world.resource::<PongState>(),
+            PongState::GameOver(Side::Left)
+        );
+        assert_eq!(app.world.resource::<Score>().left_games, 2);
     }
 }
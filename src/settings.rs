@@ -0,0 +1,225 @@
+use bevy::prelude::*;
+use iyes_loopless::prelude::*;
+
+use crate::{
+    args::{Args, InputDelay, MAX_INPUT_DELAY, MAX_PREDICTION_WINDOW},
+    types::GameState,
+    util::despawn_with,
+};
+
+/// Main menu button that opens the settings
+#[derive(Component)]
+pub(crate) struct SettingsButton;
+
+#[derive(Component)]
+struct SettingsUI;
+
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+enum Setting {
+    InputDelay,
+    PredictionWindow,
+}
+
+/// Steps a setting back or forward when clicked
+#[derive(Component)]
+struct SettingButton {
+    setting: Setting,
+    step: i32,
+}
+
+#[derive(Component)]
+struct SettingText(Setting);
+
+/// Next input delay in the order auto, 0, 1, ..., wrapping around at both ends
+fn step_input_delay(input_delay: InputDelay, step: i32) -> InputDelay {
+    let options = MAX_INPUT_DELAY as i32 + 2;
+    let index = match input_delay {
+        InputDelay::Auto => 0,
+        InputDelay::Fixed(frames) => frames as i32 + 1,
+    };
+    match (index + step).rem_euclid(options) {
+        0 => InputDelay::Auto,
+        index => InputDelay::Fixed(index as usize - 1),
+    }
+}
+
+fn step_prediction_window(frames: usize, step: i32) -> usize {
+    (frames as i32 - 1 + step).rem_euclid(MAX_PREDICTION_WINDOW as i32) as usize + 1
+}
+
+fn setting_label(setting: Setting, args: &Args) -> String {
+    match setting {
+        Setting::InputDelay => format!("Input delay: {}", args.input_delay),
+        Setting::PredictionWindow => format!("Prediction window: {}", args.max_prediction),
+    }
+}
+
+fn setup_settings(mut commands: Commands, asset_server: Res<AssetServer>, args: Res<Args>) {
+    let font = asset_server.load("fonts/PublicPixel-z84yD.ttf");
+    let text_style = TextStyle {
+        font: font.clone(),
+        font_size: 32.,
+        ..default()
+    };
+    let button_bundle = ButtonBundle {
+        style: Style {
+            margin: UiRect::all(Val::Px(20.)),
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            ..default()
+        },
+        background_color: Color::BLACK.into(),
+        ..default()
+    };
+
+    commands
+        .spawn((
+            SettingsUI,
+            NodeBundle {
+                style: Style {
+                    size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                    flex_direction: FlexDirection::ColumnReverse,
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            for setting in [Setting::InputDelay, Setting::PredictionWindow] {
+                parent
+                    .spawn(NodeBundle {
+                        style: Style {
+                            align_items: AlignItems::Center,
+                            ..default()
+                        },
+                        ..default()
+                    })
+                    .with_children(|parent| {
+                        parent
+                            .spawn((SettingButton { setting, step: -1 }, button_bundle.clone()))
+                            .with_children(|parent| {
+                                parent.spawn(TextBundle::from_section("<", text_style.clone()));
+                            });
+                        parent.spawn((
+                            SettingText(setting),
+                            TextBundle::from_section(
+                                setting_label(setting, &args),
+                                text_style.clone(),
+                            ),
+                        ));
+                        parent
+                            .spawn((SettingButton { setting, step: 1 }, button_bundle.clone()))
+                            .with_children(|parent| {
+                                parent.spawn(TextBundle::from_section(">", text_style.clone()));
+                            });
+                    });
+            }
+
+            parent.spawn(
+                TextBundle::from_section(
+                    format!(
+                        "Online matches run at {} fps, set with --fps\n\nEscape to go back",
+                        args.fps
+                    ),
+                    TextStyle {
+                        font,
+                        font_size: 16.,
+                        ..default()
+                    },
+                )
+                .with_text_alignment(TextAlignment::CENTER)
+                .with_style(Style {
+                    margin: UiRect::all(Val::Px(20.)),
+                    ..default()
+                }),
+            );
+        });
+}
+
+fn open_settings(
+    mut commands: Commands,
+    interaction_query: Query<&Interaction, (Changed<Interaction>, With<SettingsButton>)>,
+) {
+    if interaction_query
+        .iter()
+        .any(|interaction| *interaction == Interaction::Clicked)
+    {
+        commands.insert_resource(NextState(GameState::Settings));
+    }
+}
+
+fn setting_buttons(
+    mut args: ResMut<Args>,
+    interaction_query: Query<(&Interaction, &SettingButton), Changed<Interaction>>,
+) {
+    for (interaction, button) in &interaction_query {
+        if *interaction != Interaction::Clicked {
+            continue;
+        }
+        match button.setting {
+            Setting::InputDelay => {
+                args.input_delay = step_input_delay(args.input_delay, button.step);
+            }
+            Setting::PredictionWindow => {
+                args.max_prediction = step_prediction_window(args.max_prediction, button.step);
+            }
+        }
+    }
+}
+
+fn setting_texts(args: Res<Args>, mut text_query: Query<(&mut Text, &SettingText)>) {
+    if !args.is_changed() {
+        return;
+    }
+    for (mut text, setting) in &mut text_query {
+        text.sections[0].value = setting_label(setting.0, &args);
+    }
+}
+
+fn leave_settings(mut commands: Commands, keyboard_input: Res<Input<KeyCode>>) {
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        commands.insert_resource(NextState(GameState::MainMenu));
+    }
+}
+
+/// Settings of online matches, opened from the main menu
+pub(crate) struct SettingsPlugin;
+
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_enter_system(GameState::Settings, setup_settings)
+            .add_exit_system(GameState::Settings, despawn_with::<SettingsUI>)
+            .add_system(open_settings.run_in_state(GameState::MainMenu))
+            .add_system_set(
+                ConditionSet::new()
+                    .run_in_state(GameState::Settings)
+                    .with_system(setting_buttons)
+                    .with_system(setting_texts)
+                    .with_system(leave_settings)
+                    .into(),
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_wrap_around() {
+        assert_eq!(step_input_delay(InputDelay::Auto, 1), InputDelay::Fixed(0));
+        assert_eq!(
+            step_input_delay(InputDelay::Auto, -1),
+            InputDelay::Fixed(MAX_INPUT_DELAY)
+        );
+        assert_eq!(
+            step_input_delay(InputDelay::Fixed(MAX_INPUT_DELAY), 1),
+            InputDelay::Auto
+        );
+        assert_eq!(step_prediction_window(1, -1), MAX_PREDICTION_WINDOW);
+        assert_eq!(step_prediction_window(MAX_PREDICTION_WINDOW, 1), 1);
+        assert_eq!(step_prediction_window(12, 1), 13);
+    }
+}
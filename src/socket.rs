@@ -0,0 +1,220 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bevy::prelude::*;
+use ggrs::{Message, NonBlockingSocket};
+use matchbox_socket::WebRtcSocket;
+use serde::{Deserialize, Serialize};
+
+use crate::pong::PongState;
+
+/// First byte of every packet, telling GGRS packets apart from our own messages
+const GGRS_CHANNEL: u8 = 0;
+const GAME_CHANNEL: u8 = 1;
+
+/// What a peer joined the room for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) enum Role {
+    Player,
+    Spectator,
+}
+
+/// What a peer announces about itself for as long as it is in the room
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Hello {
+    pub(crate) role: Role,
+    /// Simulation rate of the match, every peer needs the same one
+    pub(crate) fps: u32,
+    /// Hash of the game config, every peer needs the same one too
+    pub(crate) config: u64,
+}
+
+/// What a spectator that turned up after the start sees of the match. GGRS can't add spectators to
+/// a running session, so the host streams these to them instead of the inputs.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct MatchSnapshot {
+    pub(crate) ball: Vec2,
+    pub(crate) left_paddle: Vec2,
+    pub(crate) right_paddle: Vec2,
+    /// Points and games won, left then right
+    pub(crate) score: [u32; 4],
+    pub(crate) clock: Duration,
+    pub(crate) state: PongState,
+}
+
+/// Messages the game sends to its peers next to the GGRS traffic
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum GameMessage {
+    /// Sent while measuring the round trip time, carries the sender's clock in milliseconds
+    Ping(u32),
+    /// Answer to a ping, with the clock of the ping echoed back
+    Pong(u32),
+    /// Announces the sender, repeated for as long as it is in the room
+    Hello(Hello),
+    /// Tells a peer that announced itself that the match started without it
+    MatchStarted,
+    /// The state of the match for a late spectator, sent by the host every frame
+    Snapshot(MatchSnapshot),
+}
+
+impl GameMessage {
+    fn encode(&self) -> Vec<u8> {
+        let (kind, payload) = match *self {
+            GameMessage::Ping(millis) => (0, millis.to_le_bytes().to_vec()),
+            GameMessage::Pong(millis) => (1, millis.to_le_bytes().to_vec()),
+            GameMessage::Hello(hello) => (2, bincode::serialize(&hello).unwrap()),
+            GameMessage::MatchStarted => (3, 0u32.to_le_bytes().to_vec()),
+            GameMessage::Snapshot(snapshot) => (4, bincode::serialize(&snapshot).unwrap()),
+        };
+        let mut packet = vec![GAME_CHANNEL, kind];
+        packet.extend_from_slice(&payload);
+        packet
+    }
+
+    /// Decode a message without the channel byte, `None` if it isn't one we know
+    fn decode(packet: &[u8]) -> Option<Self> {
+        let (&kind, payload) = packet.split_first()?;
+        match kind {
+            2 => return bincode::deserialize(payload).ok().map(GameMessage::Hello),
+            4 => {
+                return bincode::deserialize(payload)
+                    .ok()
+                    .map(GameMessage::Snapshot)
+            }
+            _ => {}
+        }
+        let payload = u32::from_le_bytes(payload.try_into().ok()?);
+        match kind {
+            0 => Some(GameMessage::Ping(payload)),
+            1 => Some(GameMessage::Pong(payload)),
+            3 => Some(GameMessage::MatchStarted),
+            _ => None,
+        }
+    }
+}
+
+struct Inbox {
+    socket: WebRtcSocket,
+    ggrs: Vec<(String, Message)>,
+    game: Vec<(String, GameMessage)>,
+}
+
+impl Inbox {
+    /// Sort everything the socket received into the GGRS and game messages
+    fn poll(&mut self) {
+        for (peer, packet) in self.socket.receive() {
+            match packet.split_first() {
+                Some((&GGRS_CHANNEL, packet)) => match bincode::deserialize(packet) {
+                    Ok(message) => self.ggrs.push((peer, message)),
+                    Err(err) => warn!("dropping malformed GGRS packet from {peer}: {err}"),
+                },
+                Some((&GAME_CHANNEL, packet)) => match GameMessage::decode(packet) {
+                    Some(message) => self.game.push((peer, message)),
+                    None => warn!("dropping unknown message from {peer}"),
+                },
+                _ => warn!("dropping packet on an unknown channel from {peer}"),
+            }
+        }
+    }
+}
+
+/// Matchbox socket shared between the GGRS session and the game, so the game can keep talking to
+/// its peers after the session took over the socket.
+#[derive(Resource, Clone)]
+pub(crate) struct MatchSocket(Arc<Mutex<Inbox>>);
+
+impl MatchSocket {
+    pub(crate) fn new(socket: WebRtcSocket) -> Self {
+        MatchSocket(Arc::new(Mutex::new(Inbox {
+            socket,
+            ggrs: Vec::new(),
+            game: Vec::new(),
+        })))
+    }
+
+    pub(crate) fn accept_new_connections(&self) -> Vec<String> {
+        self.0.lock().unwrap().socket.accept_new_connections()
+    }
+
+    pub(crate) fn connected_peers(&self) -> Vec<String> {
+        self.0.lock().unwrap().socket.connected_peers()
+    }
+
+    /// Our own peer id, as the other peers know us
+    pub(crate) fn id(&self) -> String {
+        self.0.lock().unwrap().socket.id().clone()
+    }
+
+    pub(crate) fn send(&self, message: GameMessage, peer: &str) {
+        let packet = message.encode().into_boxed_slice();
+        self.0.lock().unwrap().socket.send(packet, peer);
+    }
+
+    /// Take the game messages received since the last call
+    pub(crate) fn receive(&self) -> Vec<(String, GameMessage)> {
+        let mut inbox = self.0.lock().unwrap();
+        inbox.poll();
+        std::mem::take(&mut inbox.game)
+    }
+}
+
+impl NonBlockingSocket<String> for MatchSocket {
+    fn send_to(&mut self, msg: &Message, addr: &String) {
+        let mut packet = vec![GGRS_CHANNEL];
+        packet.extend(bincode::serialize(msg).unwrap());
+        self.0
+            .lock()
+            .unwrap()
+            .socket
+            .send(packet.into_boxed_slice(), addr.as_str());
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(String, Message)> {
+        let mut inbox = self.0.lock().unwrap();
+        inbox.poll();
+        std::mem::take(&mut inbox.ggrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pong::Side;
+
+    #[test]
+    fn game_messages_survive_encoding() {
+        for message in [
+            GameMessage::Ping(0),
+            GameMessage::Pong(u32::MAX),
+            GameMessage::Hello(Hello {
+                role: Role::Player,
+                fps: 60,
+                config: 0,
+            }),
+            GameMessage::Hello(Hello {
+                role: Role::Spectator,
+                fps: 240,
+                config: u64::MAX,
+            }),
+            GameMessage::MatchStarted,
+            GameMessage::Snapshot(MatchSnapshot {
+                ball: Vec2::new(-12.5, 300.),
+                left_paddle: Vec2::new(-640., 0.),
+                right_paddle: Vec2::new(640., -42.),
+                score: [3, 7, 1, 0],
+                clock: Duration::from_millis(61_250),
+                state: PongState::GameOver(Side::Right),
+            }),
+        ] {
+            let packet = message.encode();
+            assert_eq!(packet[0], GAME_CHANNEL);
+            assert_eq!(GameMessage::decode(&packet[1..]), Some(message));
+        }
+        assert_eq!(GameMessage::decode(&[7, 0, 0, 0, 0]), None);
+        assert_eq!(GameMessage::decode(&[0, 0]), None);
+        assert_eq!(GameMessage::decode(&[2, 7, 0, 0, 0]), None);
+        assert_eq!(GameMessage::decode(&[4, 1, 2]), None);
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_ggrs::{Rollback, Session};
+use ggrs::SessionBuilder;
+use iyes_loopless::prelude::*;
+
+use crate::{
+    args::Args,
+    online::{GGRSConfig, RollbackFrame, SideAssignment},
+    pong::{Ball, BallSpeed, MatchClock, Paddle, PongState, Position, RallyLength, Score},
+    types::{GameState, GameType},
+};
+
+/// Checksums of every piece of rollback state, keyed by frame, from the first time each frame
+/// was simulated.
+///
+/// SyncTest resimulates every frame with the same inputs, so any checksum that comes out
+/// different the second time around points straight at the non-deterministic state.
+#[derive(Resource, Debug, Default)]
+pub(crate) struct ChecksumHistory {
+    frames: HashMap<u32, HashMap<String, u64>>,
+    /// Number of frames kept around, older frames are never resimulated
+    window: u32,
+    /// Total number of mismatched checksums seen so far
+    pub(crate) mismatches: usize,
+}
+
+impl ChecksumHistory {
+    pub(crate) fn new(check_distance: usize) -> Self {
+        ChecksumHistory {
+            window: check_distance as u32 + 1,
+            ..default()
+        }
+    }
+
+    /// Remember the checksums of a frame, or compare them with the remembered ones if the frame
+    /// was simulated before. Returns the names of the state that differs, sorted.
+    fn check(&mut self, frame: u32, checksums: HashMap<String, u64>) -> Vec<String> {
+        let Some(first) = self.frames.get(&frame) else {
+            let window = self.window;
+            self.frames.retain(|&old, _| old + window > frame);
+            self.frames.insert(frame, checksums);
+            return Vec::new();
+        };
+
+        let mut differing: Vec<String> = first
+            .keys()
+            .chain(checksums.keys())
+            .filter(|&name| first.get(name) != checksums.get(name))
+            .cloned()
+            .collect();
+        differing.sort();
+        differing.dedup();
+        self.mismatches += differing.len();
+        differing
+    }
+}
+
+fn checksum(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Checksum the rollback state at the end of a frame and report anything that differs from the
+/// first simulation of that frame.
+#[allow(clippy::type_complexity)]
+pub(crate) fn check_rollback_state(
+    frame: Res<RollbackFrame>,
+    mut history: ResMut<ChecksumHistory>,
+    entities: Query<(&Rollback, Option<&Position>, Option<&Ball>, Option<&Paddle>)>,
+    (score, pong_state, clock, sides, rally, ball_speed): (
+        Res<Score>,
+        Res<PongState>,
+        Res<MatchClock>,
+        Res<SideAssignment>,
+        Res<RallyLength>,
+        Res<BallSpeed>,
+    ),
+) {
+    let mut checksums = HashMap::default();
+    checksums.insert("Score".to_string(), checksum(&*score));
+    checksums.insert("PongState".to_string(), checksum(&*pong_state));
+    checksums.insert("MatchClock".to_string(), checksum(&*clock));
+    checksums.insert("SideAssignment".to_string(), checksum(&*sides));
+    checksums.insert("RallyLength".to_string(), checksum(&*rally));
+    checksums.insert("BallSpeed".to_string(), checksum(&*ball_speed));
+    for (rollback, position, ball, paddle) in &entities {
+        let id = rollback.id();
+        if let Some(position) = position {
+            checksums.insert(format!("Position of entity {id}"), checksum(position));
+        }
+        if let Some(ball) = ball {
+            checksums.insert(format!("Ball of entity {id}"), checksum(ball));
+        }
+        if let Some(paddle) = paddle {
+            checksums.insert(format!("Paddle of entity {id}"), checksum(paddle));
+        }
+    }
+
+    for state in history.check(frame.0, checksums) {
+        error!("SyncTest mismatch on frame {}: {state} differs", frame.0);
+    }
+}
+
+fn start_synctest_session(mut commands: Commands, args: Res<Args>) {
+    info!(
+        "starting SyncTest session with check distance {}",
+        args.check_distance
+    );
+
+    let session = SessionBuilder::<GGRSConfig>::new()
+        .with_num_players(2)
+        .with_max_prediction_window(args.check_distance + 1)
+        .with_check_distance(args.check_distance)
+        .start_synctest_session()
+        .expect("Invalid synctest session");
+
+    commands.insert_resource(ChecksumHistory::new(args.check_distance));
+    commands.insert_resource(Session::SyncTestSession(session));
+}
+
+/// Runs the online game against itself on a single machine, enabled with `--synctest`
+pub(crate) struct SyncTestPlugin;
+
+impl Plugin for SyncTestPlugin {
+    fn build(&self, app: &mut App) {
+        // the session starts on the first frame in game, together with the rollback tags of the
+        // court, so its first snapshot already contains them
+        app.add_system(
+            start_synctest_session
+                .run_in_state(GameType::Online)
+                .run_in_state(GameState::Ingame)
+                .run_unless_resource_exists::<Session<GGRSConfig>>(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checksums(values: &[(&str, u64)]) -> HashMap<String, u64> {
+        values
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value))
+            .collect()
+    }
+
+    #[test]
+    fn resimulated_frames_report_the_differing_state() {
+        let mut history = ChecksumHistory::new(2);
+
+        let first = checksums(&[("Score", 1), ("Position of entity 2", 2)]);
+        assert!(history.check(10, first.clone()).is_empty());
+        assert!(history.check(10, first).is_empty());
+
+        let resimulated = checksums(&[("Score", 1), ("Position of entity 2", 3)]);
+        assert_eq!(
+            history.check(10, resimulated),
+            vec!["Position of entity 2".to_string()]
+        );
+        assert_eq!(history.mismatches, 1);
+
+        // frames outside the check distance are forgotten
+        history.check(13, checksums(&[]));
+        assert!(history.check(10, checksums(&[("Score", 5)])).is_empty());
+    }
+}
@@ -15,5 +15,8 @@ pub(crate) enum GameState {
     MainMenu,
     #[cfg(feature = "online")]
     Lobby,
+    #[cfg(feature = "online")]
+    Settings,
+    Controls,
     Ingame,
 }